@@ -1,10 +1,32 @@
 use std::cmp::min;
+use std::sync::Arc;
+
+use crate::error::AsmError;
+use crate::span::{SourceFile, Span, Spanned};
+
+/// Separator of the names made up by the assembler, '#' is lexed
+/// as HASH so no source can declare such a name
+const GENERATED_SEPARATOR: char = '#';
+
+/// Name made up from `name` and `suffix`
+pub fn generated_name(name: &str, suffix: impl std::fmt::Display) -> String {
+    format!("{}{}{}", name, GENERATED_SEPARATOR, suffix)
+}
+
+/// `name` as written in the source, without the suffixes made up by the assembler
+pub fn source_name(name: &str) -> String {
+    name
+        .split("::")
+        .map(|part| part.split(GENERATED_SEPARATOR).next().unwrap_or(part))
+        .collect::<Vec<&str>>()
+        .join("::")
+}
 
 // https://famicom.party/book/05-6502assembly/
 #[derive(Debug, Clone, Eq)]
 pub enum Token {
     DIRECTIVE(String),  // .LITERAL (.segment)
-    LITERAL(String),    // [\w_]+ 
+    LITERAL(String),    // [\w_]+ | @[\w_]+ (local label) | [\w_]+::[\w_]+ (scoped)
     COMMENT(String),    // ;(.*)\n
     COMMA,              // ,
     COLON,              // :
@@ -17,6 +39,21 @@ pub enum Token {
     MULT,               // *
     DIV,                // /
     EQUAL,              // =
+    MOD,                // % (not followed by a binary digit)
+    AND,                // &
+    OR,                 // |
+    XOR,                // ^
+    NOT,                // ~
+    SHL,                // <<
+    SHR,                // >>
+    LT,                 // <
+    GT,                 // >
+    LTE,                // <=
+    GTE,                // >=
+    NOTEQUAL,           // <> | !=
+    LAND,               // &&
+    LOR,                // ||
+    LNOT,               // !
     DEC(String),        // [0-9]+
     HEX(String),        // \$[0-9abdef]+
     BIN(String),        // %[01]+
@@ -32,22 +69,57 @@ impl PartialEq for Token {
 }
 
 pub struct AsmLexer {
+    file: Arc<SourceFile>,
     source: Vec<char>,
     cursor: usize,
+    /// Number of chars trimmed at the start of the file
+    offset: usize,
+    /// Byte position of each char of the file
+    byte_pos: Vec<usize>,
+    /// Char position of each line of the file
+    line_starts: Vec<usize>
 }
 
 impl AsmLexer {
-    pub fn new(source: &String) -> Self {
+    pub fn new(source: &str) -> Self {
+        Self::from_file(SourceFile::new("<source>", source))
+    }
+
+    pub fn from_file(file: Arc<SourceFile>) -> Self {
+        let text = &file.text;
+        let trimmed = text.trim_start();
+        let offset = text[..text.len() - trimmed.len()].chars().count();
+        let mut byte_pos = vec![];
+        let mut line_starts = vec![0];
+        for (i, (pos, c)) in text.char_indices().enumerate() {
+            byte_pos.push(pos);
+            if c == '\n' {
+                line_starts.push(i + 1);
+            }
+        }
+        byte_pos.push(text.len());
         Self {
-            source: source
-                .trim()
+            source: trimmed
+                .trim_end()
                 .chars()
                 .collect(),
             cursor: 0,
+            offset,
+            byte_pos,
+            line_starts,
+            file
         }
     }
 
-    pub fn tokenize(&mut self) -> Result<Vec<Token>, String> {
+    pub fn tokenize(&mut self) -> Result<Vec<Token>, AsmError> {
+        let tokens = self.tokenize_spanned()?;
+        Ok(tokens
+            .into_iter()
+            .map(|tk| tk.value)
+            .collect())
+    }
+
+    pub fn tokenize_spanned(&mut self) -> Result<Vec<Spanned<Token>>, AsmError> {
         let mut prog = Vec::new();
         self.cursor = 0;
         loop {
@@ -55,53 +127,102 @@ impl AsmLexer {
             if self.is_eof() {
                 break;
             }
-
-            if self.is_literal() && !self.is_dec() && *self.curr() != '.' {
-                let lit = self.consume_literal()?;
-                match lit {
-                    Token::LITERAL(s) => {
-                        self.back(s.len());
-                        prog.push(self.consume(&s, None)?);
-                    },
-                    _ => {}
-                }
-                continue;
+            let start = self.cursor;
+            let token = self
+                .consume_token()
+                .map_err(|e| e.or_at(&self.span(self.cursor, self.cursor + 1)))?;
+            if let Some(value) = token {
+                let span = self.span(start, self.cursor);
+                prog.push(Spanned { value, span });
             }
+        }
+        let span = self.span(self.cursor, self.cursor);
+        prog.push(Spanned { value: Token::EOF, span });
+        Ok(prog)
+    }
 
-            let c = *self.curr();
-            if c == ' ' || c == '\t' {
-                self.consume_whitespaces()?;
-                continue;
-            }
+    /// Location of the chars from `start` to `end` (excluded)
+    fn span(&self, start: usize, end: usize) -> Span {
+        let last = self.byte_pos.len() - 1;
+        let start = min(self.offset + start, last);
+        let end = min(self.offset + end, last);
+        let line = self.line_starts.partition_point(|pos| *pos <= start);
+        Span {
+            file: self.file.clone(),
+            line,
+            column: start - self.line_starts[line - 1] + 1,
+            start: self.byte_pos[start],
+            end: self.byte_pos[end],
+            expanded_from: None
+        }
+    }
 
-            let res = match c {
-                '.' => self.consume_directive(),
-                ')' => self.consume(")", Some(Token::PARENTCLOSE)),
-                '(' => self.consume("(", Some(Token::PARENTOPEN)),
-                '#' => self.consume("#", Some(Token::HASH)),
-                ',' => self.consume(",", Some(Token::COMMA)),
-                ':' => self.consume(":", Some(Token::COLON)),
-                '-' => self.consume("-", Some(Token::MINUS)),
-                '+' => self.consume("+", Some(Token::PLUS)),
-                '*' => self.consume("*", Some(Token::MULT)),
-                '/' => self.consume("/", Some(Token::DIV)),
-                '=' => self.consume("=", Some(Token::EQUAL)),
-                ';' => self.consume_comment(),
-                '\n' => self.consume_endlines(),
-                '\r' => self.consume_endlines(),
-                '$' => self.consume_hex(),
-                '%' => self.consume_bin(),
-                '"' => self.consume_string(),
-                '\'' => self.consume_char(),
-                '0' ..= '9' => self.consume_dec(),
-                _ => {
-                    return Err(format!("{:?} is not a supported character", c));
-                }
-            };
-            prog.push(res?);
+    /// Consume the next token, whitespaces are skipped
+    fn consume_token(&mut self) -> Result<Option<Token>, AsmError> {
+        if self.is_literal() && !self.is_dec() && *self.curr() != '.' {
+            return self.consume_qualified_literal().map(Some);
         }
-        prog.push(Token::EOF);
-        Ok(prog)
+
+        let c = *self.curr();
+        if c == ' ' || c == '\t' {
+            self.consume_whitespaces()?;
+            return Ok(None);
+        }
+
+        let res = match c {
+            '.' => self.consume_directive(),
+            ')' => self.consume(")", Some(Token::PARENTCLOSE)),
+            '(' => self.consume("(", Some(Token::PARENTOPEN)),
+            '#' => self.consume("#", Some(Token::HASH)),
+            ',' => self.consume(",", Some(Token::COMMA)),
+            ':' => self.consume(":", Some(Token::COLON)),
+            '-' => self.consume("-", Some(Token::MINUS)),
+            '+' => self.consume("+", Some(Token::PLUS)),
+            '*' => self.consume("*", Some(Token::MULT)),
+            '/' => self.consume("/", Some(Token::DIV)),
+            '=' => self.consume("=", Some(Token::EQUAL)),
+            '@' => self.consume_local(),
+            '^' => self.consume("^", Some(Token::XOR)),
+            '~' => self.consume("~", Some(Token::NOT)),
+            '&' => match self.peek() {
+                '&' => self.consume("&&", Some(Token::LAND)),
+                _ => self.consume("&", Some(Token::AND))
+            },
+            '|' => match self.peek() {
+                '|' => self.consume("||", Some(Token::LOR)),
+                _ => self.consume("|", Some(Token::OR))
+            },
+            '!' => match self.peek() {
+                '=' => self.consume("!=", Some(Token::NOTEQUAL)),
+                _ => self.consume("!", Some(Token::LNOT))
+            },
+            '<' => match self.peek() {
+                '<' => self.consume("<<", Some(Token::SHL)),
+                '=' => self.consume("<=", Some(Token::LTE)),
+                '>' => self.consume("<>", Some(Token::NOTEQUAL)),
+                _ => self.consume("<", Some(Token::LT))
+            },
+            '>' => match self.peek() {
+                '>' => self.consume(">>", Some(Token::SHR)),
+                '=' => self.consume(">=", Some(Token::GTE)),
+                _ => self.consume(">", Some(Token::GT))
+            },
+            ';' => self.consume_comment(),
+            '\n' => self.consume_endlines(),
+            '\r' => self.consume_endlines(),
+            '$' => self.consume_hex(),
+            '%' => match self.peek() {
+                '0' | '1' => self.consume_bin(),
+                _ => self.consume("%", Some(Token::MOD))
+            },
+            '"' => self.consume_string(),
+            '\'' => self.consume_char(),
+            '0' ..= '9' => self.consume_dec(),
+            _ => {
+                return Err(AsmError::lex(format!("{:?} is not a supported character", c)));
+            }
+        };
+        res.map(Some)
     }
 
     fn curr(&self) -> &char {
@@ -121,9 +242,7 @@ impl AsmLexer {
 
     fn is_hex(&mut self) -> bool {
         let c = *self.curr();
-        c >= '0' && c <= '9' 
-        || c >= 'a' && c <= 'f'
-        || c >= 'A' && c <= 'F'
+        c.is_ascii_hexdigit()
     }
 
     fn is_eof(&self) -> bool {
@@ -139,72 +258,68 @@ impl AsmLexer {
         self.curr().is_alphanumeric() || "_.".contains(*self.curr())
     }
 
-    fn next(&mut self) -> &char {
-        self.cursor = min(self.source.len(), self.cursor + 1);
-        return self.curr();
+    fn peek(&self) -> char {
+        *self
+            .source
+            .get(self.cursor + 1)
+            .unwrap_or(&'\0')
     }
 
-    fn back(&mut self, count: usize) -> &char {
-        if self.cursor > (count - 1) {
-            self.cursor -= count;
-        }
-        return self.curr();
+    fn next(&mut self) -> &char {
+        self.cursor = min(self.source.len(), self.cursor + 1);
+        self.curr()
     }
 
-    fn consume(&mut self, s: &str, ret: Option<Token>) -> Result<Token, String> {
+    fn consume(&mut self, s: &str, ret: Option<Token>) -> Result<Token, AsmError> {
         for c in s.chars() {
             if *self.curr() != c {
-                return Err(format!("{:?} was expected, got {:?}", c, *self.curr()));
+                return Err(AsmError::lex(format!("{:?} was expected, got {:?}", c, *self.curr())));
             }
             self.next();
         }
 
-        if ret.is_some() {
-            return Ok(ret.unwrap());
+        if let Some(ret) = ret {
+            return Ok(ret);
         }
         Ok(Token::LITERAL(s.to_string()))
     }
 
-    fn consume_directive(&mut self) -> Result<Token, String> {
+    fn consume_directive(&mut self) -> Result<Token, AsmError> {
         self.consume(".", None)?;
-        match self.consume_literal() {
-            Ok(lit) => {
-                match lit {
-                    Token::LITERAL(s) => Ok(Token::DIRECTIVE(s)),
-                    tk =>  panic!("literal was expected after '.', got {:?}", tk)
-                }
-            },
-            Err(e) => Err(e)
+        match self.consume_literal()? {
+            Token::LITERAL(s) => Ok(Token::DIRECTIVE(s)),
+            tk => Err(AsmError::lex(format!("literal was expected after '.', got {:?}", tk)))
         }
     }
 
-    fn consume_hex(&mut self) -> Result<Token, String> {
+    fn consume_hex(&mut self) -> Result<Token, AsmError> {
         self.consume("$", None)?;
         let mut s = String::from("");
         while self.is_hex() {
             s.push(*self.curr());
             self.next();
         }
-        if s.len() == 0 || s.len() > 4 {
-            return Err(format!("8 bits hex was expected, got '${}'", self.curr()));
+        // wider values are range checked where they are used
+        if s.is_empty() {
+            return Err(AsmError::lex(format!("hex digits were expected, got '${}'", self.curr())));
         }
         Ok(Token::HEX(s))
     }
 
-    fn consume_bin(&mut self) -> Result<Token, String> {
+    fn consume_bin(&mut self) -> Result<Token, AsmError> {
         self.consume("%", None)?;
         let mut s = String::from("");
         while self.is_bin() {
             s.push(*self.curr());
             self.next();
         }
-        if s.len() == 0 || s.len() > 8 {
-            return Err(format!("8 bits binary was expected, got '%{}'", self.curr()));
+        if s.is_empty() || s.len() > 8 {
+            return Err(AsmError::lex(format!("8 bits binary was expected, got '%{}'", self.curr())));
         }
         Ok(Token::BIN(s))
     }
 
-    fn consume_dec(&mut self) -> Result<Token, String> {
+    fn consume_dec(&mut self) -> Result<Token, AsmError> {
         let mut s = String::from("");
         while self.is_dec() {
             s.push(*self.curr());
@@ -213,22 +328,19 @@ impl AsmLexer {
         Ok(Token::DEC(s))
     }
 
-    fn consume_whitespaces(&mut self) -> Result<(), String> {
+    fn consume_whitespaces(&mut self) -> Result<(), AsmError> {
         let mut ok = false;
         while *self.curr() == ' ' || *self.curr() == '\t' {
             ok = true;
             self.next();
         }
-        if *self.curr() == ' ' {
-            panic!("no");
-        }
         if ok {
             return Ok(());
         }
-        Err(format!("whitespace or newline was expected, got {:?}", self.curr()))
+        Err(AsmError::lex(format!("whitespace or newline was expected, got {:?}", self.curr())))
     }
 
-    fn consume_endlines(&mut self) -> Result<Token, String> {
+    fn consume_endlines(&mut self) -> Result<Token, AsmError> {
         let mut ok = false;
         while self.is_endline() {
             ok = true;
@@ -237,10 +349,10 @@ impl AsmLexer {
         if ok {
             return Ok(Token::NEWLINE);
         }
-        Err(format!("newline was expected, got {:?}", self.curr()))
+        Err(AsmError::lex(format!("newline was expected, got {:?}", self.curr())))
     }
 
-    fn consume_literal(&mut self) -> Result<Token, String> {
+    fn consume_literal(&mut self) -> Result<Token, AsmError> {
         let mut tk = String::from("");
         
         while self.is_literal() {
@@ -248,14 +360,40 @@ impl AsmLexer {
             self.next();
         }
 
-        if tk.len() == 0 {
-            Err(format!("alphanum, _ or . was expected, got {:?}", self.curr()))
+        if tk.is_empty() {
+            Err(AsmError::lex(format!("alphanum, _ or . was expected, got {:?}", self.curr())))
         } else {
             Ok(Token::LITERAL(tk))
         }
     }
 
-    fn consume_string(&mut self) -> Result<Token, String> {
+    /// Literal possibly prefixed by scopes: proc::label
+    fn consume_qualified_literal(&mut self) -> Result<Token, AsmError> {
+        let mut tk = String::from("");
+        loop {
+            match self.consume_literal()? {
+                Token::LITERAL(s) => tk.push_str(&s),
+                tk => return Err(AsmError::lex(format!("literal was expected, got {:?}", tk)))
+            }
+            let after = self.source.get(self.cursor + 2).copied().unwrap_or('\0');
+            if *self.curr() != ':' || self.peek() != ':' || !(after.is_alphanumeric() || after == '_') {
+                break;
+            }
+            self.consume("::", None)?;
+            tk.push_str("::");
+        }
+        Ok(Token::LITERAL(tk))
+    }
+
+    fn consume_local(&mut self) -> Result<Token, AsmError> {
+        self.consume("@", None)?;
+        match self.consume_literal()? {
+            Token::LITERAL(s) => Ok(Token::LITERAL(format!("@{}", s))),
+            tk => Err(AsmError::lex(format!("literal was expected after '@', got {:?}", tk)))
+        }
+    }
+
+    fn consume_string(&mut self) -> Result<Token, AsmError> {
         self.consume("\"", None)?;
         let mut s = String::from("");
         while *self.curr() != '"' && !self.is_endline() && !self.is_eof()  {
@@ -269,7 +407,7 @@ impl AsmLexer {
         Ok(Token::STR(s))
     }
 
-    fn consume_char(&mut self) -> Result<Token, String> {
+    fn consume_char(&mut self) -> Result<Token, AsmError> {
         self.consume("\'", None)?;
         if *self.curr() == '\\' {
             // escape
@@ -281,7 +419,7 @@ impl AsmLexer {
         Ok(Token::CHAR(value))
     }
 
-    fn consume_comment(&mut self) -> Result<Token, String> {
+    fn consume_comment(&mut self) -> Result<Token, AsmError> {
         self.consume(";", None)?;
 
         let mut tk = String::from("");
@@ -1,10 +1,15 @@
 use std::cmp::{min, max};
-use std::collections::HashMap;
+use std::fmt;
+use std::collections::{HashMap, HashSet};
 
 use crate::asm_lexer::Token;
+use crate::asm_preprocessor::AsmPreprocessor;
+use crate::error::AsmError;
+use crate::span::{Span, Spanned};
 use crate::opcodes::{
     Instr,
-    AdrMode, INSTR
+    AdrMode, INSTR, OPCODES,
+    zero_page_mode
 };
 
 // https://famicom.party/book/05-6502assembly/
@@ -13,92 +18,352 @@ pub enum Expr {
     DIRECTIVE(Directive),
     ASSIGN(String, MathExpr), // lit, value
     LABEL(String),
-    INSTR(Instr, AdrMode, Operand)
+    INSTR(Instr, AdrMode, Operand, bool) // instr, mode, operand, width forced by a: z: .w .b
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Operand {
     NONE,               // implied
     LABEL(String),
-    VALUE(NumericValue)  // label, variable, 1 or 2 bytes hex/dec/bin
+    VALUE(NumericValue), // label, variable, 1 or 2 bytes hex/dec/bin
+    EXPR(MathExpr)       // expression depending on a label, resolved at compile time
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MathExpr {
     BIN(Token, Box<MathExpr>, Box<MathExpr>),
-    PLACEHOLDER(String), NUM(NumericValue)
+    UNARY(Token, Box<MathExpr>),
+    PLACEHOLDER(String), NUM(NumericValue),
+    /// `*`, address of the current instruction
+    PC
+}
+
+/// Name given to the resolver for the program counter
+pub const PC_SYMBOL: &str = "*";
+
+impl MathExpr {
+    pub fn has_pc(&self) -> bool {
+        match self {
+            MathExpr::PC => true,
+            MathExpr::BIN(_, lvalue, rvalue) => lvalue.has_pc() || rvalue.has_pc(),
+            MathExpr::UNARY(_, value) => value.has_pc(),
+            MathExpr::PLACEHOLDER(_) | MathExpr::NUM(_) => false
+        }
+    }
+
+    /// Refers to a label or a variable
+    pub fn has_symbol(&self) -> bool {
+        match self {
+            MathExpr::PLACEHOLDER(_) => true,
+            MathExpr::BIN(_, lvalue, rvalue) => lvalue.has_symbol() || rvalue.has_symbol(),
+            MathExpr::UNARY(_, value) => value.has_symbol(),
+            MathExpr::PC | MathExpr::NUM(_) => false
+        }
+    }
+
+    /// Same expression with the program counter replaced by `pc`
+    pub fn with_pc(&self, pc: usize) -> MathExpr {
+        match self {
+            MathExpr::PC => MathExpr::NUM(NumericValue { value: pc as i64, size: 16 }),
+            MathExpr::BIN(op, lvalue, rvalue) => {
+                MathExpr::BIN(op.clone(), Box::new(lvalue.with_pc(pc)), Box::new(rvalue.with_pc(pc)))
+            },
+            MathExpr::UNARY(op, value) => MathExpr::UNARY(op.clone(), Box::new(value.with_pc(pc))),
+            expr => expr.clone()
+        }
+    }
+}
+
+impl fmt::Display for MathExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MathExpr::BIN(op, lvalue, rvalue) => {
+                // operands are parenthesized to keep the precedence visible
+                let operand = |expr: &MathExpr| match expr {
+                    MathExpr::BIN(..) => format!("({})", expr),
+                    _ => expr.to_string()
+                };
+                write!(f, "{} {} {}", operand(lvalue), operator_symbol(op), operand(rvalue))
+            },
+            MathExpr::UNARY(op, value) => match value.as_ref() {
+                MathExpr::BIN(..) => write!(f, "{}({})", operator_symbol(op), value),
+                _ => write!(f, "{}{}", operator_symbol(op), value)
+            },
+            MathExpr::PLACEHOLDER(name) => write!(f, "{}", name),
+            MathExpr::NUM(n) => write!(f, "{}", n.value),
+            MathExpr::PC => write!(f, "{}", PC_SYMBOL)
+        }
+    }
+}
+
+fn operator_symbol(token: &Token) -> &'static str {
+    match token {
+        Token::PLUS => "+", Token::MINUS => "-", Token::MULT => "*", Token::DIV => "/",
+        Token::MOD => "%", Token::AND => "&", Token::OR => "|", Token::XOR => "^",
+        Token::NOT => "~", Token::SHL => "<<", Token::SHR => ">>", Token::LT => "<",
+        Token::GT => ">", Token::LTE => "<=", Token::GTE => ">=", Token::EQUAL => "=",
+        Token::NOTEQUAL => "<>", Token::LAND => "&&", Token::LOR => "||", Token::LNOT => "!",
+        _ => "?"
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Directive {
     // TODO
-    // EXPORT, INCLUDE(String),
-    // ENDMACRO, MACRO(String, Vec<String>)   // .macro NAME arg1 arg2 ... argN (.*)\n endmacro
+    // EXPORT
+    // .macro / .endmacro / .include are expanded by the preprocessor
     /// .proc main 
     ENDPROC, PROC(String),
+    /// .scope [name]
+    ENDSCOPE, SCOPE(Option<String>),
     /// .segment "NAME"
     SEGMENT(String),
     /// (.db | .byte) 1, 2, 3, ... 8 bit, can be strings
-    BYTE(Vec<NumericValue>),
-    /// .dw 1, 2, 3, ... (16 bits)
-    DWORD(Vec<NumericValue>),
-    /// .res N_BYTES
-    RESERVE(usize)
+    BYTE(Vec<Operand>),
+    /// (.dw | .word) 1, 2, 3, ... (16 bits)
+    DWORD(Vec<Operand>),
+    /// .lobytes 1, 2, 3, ... low byte of each
+    LOBYTES(Vec<Operand>),
+    /// .hibytes 1, 2, 3, ... high byte of each
+    HIBYTES(Vec<Operand>),
+    /// .dbyt 1, 2, 3, ... (16 bits big-endian)
+    DBYT(Vec<Operand>),
+    /// .faraddr 1, 2, 3, ... (24 bits)
+    FARADDR(Vec<Operand>),
+    /// .res N_BYTES[, FILL], the size can depend on `*`
+    RESERVE(MathExpr, u8),
+    /// .align N[, FILL], up to the next multiple of N
+    ALIGN(usize, u8),
+    /// .fill COUNT, VALUE
+    FILL(usize, u8),
+    /// .pad $LLHH[, FILL], up to the address
+    PAD(usize, u8),
+    /// .org $LLHH
+    ORG(usize),
+    /// .illegal (on | off), unofficial opcodes allowed or not
+    ILLEGAL(bool),
+    /// .incbin "FILE"[, OFFSET[, LENGTH]]
+    INCBIN(String, usize, Option<usize>)
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct NumericValue {
-    pub value: u16,
+    /// Signed and wide, range is only checked once the value is emitted
+    pub value: i64,
+    /// Size in bits
     pub size: usize
 }
 
-fn canonicalize_number(n: &Token) -> Result<NumericValue, String> {
+impl NumericValue {
+    /// `value` with the smallest size holding it, signed or unsigned
+    pub fn sized(value: i64) -> Self {
+        let size = if (-0x80..=0xff).contains(&value) {
+            8
+        } else if (-0x8000..=0xffff).contains(&value) {
+            16
+        } else if (-0x8000_0000..=0xffff_ffff).contains(&value) {
+            32
+        } else {
+            64
+        };
+        Self { value, size }
+    }
+
+    /// Check the value fits in `size` bits, negative values are
+    /// accepted as two's complement only when `signed`
+    pub fn fits(&self, size: usize, signed: bool) -> bool {
+        let min = if signed { -(1i64 << (size - 1)) } else { 0 };
+        let max = (1i64 << size) - 1;
+        (min..=max).contains(&self.value)
+    }
+}
+
+fn parse_number(digits: &str, radix: u32) -> Result<i64, AsmError> {
+    i64::from_str_radix(digits, radix)
+        .map_err(|_| AsmError::range(format!("number {:?} does not fit in 64 bits", digits)))
+}
+
+fn canonicalize_number(n: &Token) -> Result<NumericValue, AsmError> {
     match n {
         Token::BIN(bin) => {
-            let value: u16 = u16::from_str_radix(bin, 2).unwrap();
+            let value = parse_number(bin, 2)?;
             if bin.len() > 8 {
                 return Ok(NumericValue { value, size: 16 })
             }
             Ok(NumericValue { value, size: 8 })
         },
         Token::DEC(dec) => {
-            let value: u16 = u16::from_str_radix(dec, 10).unwrap();
+            let value = parse_number(dec, 10)?;
             // ex: 256 or 00001 shall be considered as 16 bits
-            if value > 255 || dec.len() > 3 {
-                return Ok(NumericValue { value, size: 16 })
+            if dec.len() > 3 {
+                let number = NumericValue::sized(value);
+                return Ok(NumericValue { size: max(number.size, 16), ..number })
             }
-            Ok(NumericValue { value, size: 8 })
+            Ok(NumericValue::sized(value))
         },
         Token::HEX(hex) => {
-            let value: u16 = u16::from_str_radix(hex, 16).unwrap();
+            let value = parse_number(hex, 16)?;
+            // ex: $00ff is 16 bits, $123456 is wider
             if hex.len() > 2 {
-                return Ok(NumericValue { value, size: 16 })
+                let number = NumericValue::sized(value);
+                return Ok(NumericValue { size: max(number.size, 16), ..number })
             }
             Ok(NumericValue { value, size: 8 })
         },
         Token::CHAR(ch) => {
-            let value: u16 = ch.chars().next().unwrap() as u16;
-            Ok(NumericValue { value, size: 8 })
+            let value = ch.chars().next().map(|c| c as u32).unwrap_or(0);
+            if value > 0xff {
+                return Err(AsmError::range(format!("character {:?} does not fit in 8 bits", ch)));
+            }
+            Ok(NumericValue { value: value as i64, size: 8 })
         },
         token => {
-            Err(format!("operand next {:?} is not a number", token))
+            Err(AsmError::parse(format!("operand next {:?} is not a number", token)))
         }
     }
 }
 
 
-fn get_instr(s: &String) -> Result<Instr, String> {
+/// Evaluate `expr`, each placeholder is looked up with `resolve`
+pub fn eval_math_expr<F>(expr: &MathExpr, resolve: &F) -> Result<NumericValue, AsmError>
+where
+    F: Fn(&str) -> Result<NumericValue, AsmError>
+{
+    match expr {
+        MathExpr::BIN(op, lvalue, rvalue) => {
+            let left = eval_math_expr(lvalue, resolve)?;
+            let right = eval_math_expr(rvalue, resolve)?;
+            let (l, r) = (left.value, right.value);
+            let value = match op {
+                Token::PLUS => l
+                    .checked_add(r)
+                    .ok_or_else(|| AsmError::math(format!("add overflow: left {}, right {}", l, r))),
+                Token::MULT => l
+                    .checked_mul(r)
+                    .ok_or_else(|| AsmError::math(format!("multiplication overflow: left {}, right {}", l, r))),
+                Token::MINUS => l
+                    .checked_sub(r)
+                    .ok_or_else(|| AsmError::math(format!("substraction overflow: left {}, right {}", l, r))),
+                Token::DIV => l
+                    .checked_div(r)
+                    .ok_or_else(|| AsmError::math(format!("cannot divide {} by {}", l, r))),
+                Token::MOD => l
+                    .checked_rem(r)
+                    .ok_or_else(|| AsmError::math(format!("cannot divide {} by {}", l, r))),
+                Token::AND => Ok(l & r),
+                Token::OR => Ok(l | r),
+                Token::XOR => Ok(l ^ r),
+                Token::SHL => {
+                    let value = u32::try_from(r).ok().and_then(|r| l.checked_shl(r));
+                    match value {
+                        Some(value) if value >> r == l => Ok(value),
+                        _ => Err(AsmError::math(format!("shift overflow: left {}, right {}", l, r)))
+                    }
+                },
+                Token::SHR => u32::try_from(r)
+                    .ok()
+                    .and_then(|r| l.checked_shr(r))
+                    .ok_or_else(|| AsmError::math(format!("shift overflow: left {}, right {}", l, r))),
+                Token::EQUAL | Token::NOTEQUAL | Token::LT | Token::GT | Token::LTE | Token::GTE
+                | Token::LAND | Token::LOR => {
+                    let truth = match op {
+                        Token::EQUAL => l == r,
+                        Token::NOTEQUAL => l != r,
+                        Token::LT => l < r,
+                        Token::GT => l > r,
+                        Token::LTE => l <= r,
+                        Token::GTE => l >= r,
+                        Token::LAND => l != 0 && r != 0,
+                        _ => l != 0 || r != 0
+                    };
+                    // booleans are bytes
+                    return Ok(NumericValue { value: truth as i64, size: 8 });
+                },
+                token => Err(AsmError::parse(format!("binary operator {:?} not implemented", token)))
+            }?;
+            // at least as wide as the operands, wider if the result needs it
+            let number = NumericValue::sized(value);
+            Ok(NumericValue { value, size: max(number.size, max(left.size, right.size)) })
+        },
+        MathExpr::UNARY(op, value) => {
+            let number = eval_math_expr(value, resolve)?;
+            let value = match op {
+                Token::MINUS => number.value
+                    .checked_neg()
+                    .ok_or_else(|| AsmError::math(format!("negation overflow: {}", number.value)))?,
+                Token::NOT => !number.value,
+                Token::LNOT => return Ok(NumericValue { value: (number.value == 0) as i64, size: 8 }),
+                // low byte, high byte
+                Token::LT => return Ok(NumericValue { value: number.value & 0xff, size: 8 }),
+                Token::GT => return Ok(NumericValue { value: (number.value >> 8) & 0xff, size: 8 }),
+                token => return Err(AsmError::parse(format!("unary operator {:?} not implemented", token)))
+            };
+            Ok(NumericValue { value, size: max(NumericValue::sized(value).size, number.size) })
+        },
+        MathExpr::NUM(n) => Ok(n.clone()),
+        MathExpr::PLACEHOLDER(s) => resolve(s),
+        MathExpr::PC => resolve(PC_SYMBOL),
+    }
+}
+
+fn get_instr(s: &str) -> Result<Instr, AsmError> {
     match INSTR.get(&s.to_uppercase()) {
         Some(i) => Ok(i.to_owned()),
-        None => Err(format!("{:?} is not a valid instruction", s))
+        None => Err(AsmError::parse(format!("{:?} is not a valid instruction", s)))
+    }
+}
+
+/// Precedence of a binary operator, None if `token` is not one
+fn binary_level(token: &Token) -> Option<usize> {
+    BINARY_OPERATORS
+        .iter()
+        .position(|ops| ops.contains(token))
+}
+
+/// `directive` is one of `list`, whatever the case
+pub(crate) fn is_directive(directive: &str, list: &[&str]) -> bool {
+    list.iter().any(|name| name.eq_ignore_ascii_case(directive))
+}
+
+fn is_conditional(directive: &str) -> bool {
+    is_directive(directive, &["if", "ifdef", "ifndef", "elseif", "else", "endif"])
+}
+
+/// Instruction of `s` and the operand width (8 or 16) its suffix asks for: LDA.w, LDA.b
+fn split_width_suffix(s: &str) -> Result<(Instr, Option<usize>), AsmError> {
+    let Some((name, suffix)) = s.split_once('.') else {
+        return Ok((get_instr(s)?, None));
+    };
+    let width = match suffix.to_lowercase().as_str() {
+        "w" => 16,
+        "b" => 8,
+        _ => return Err(AsmError::parse(format!("{:?} is not a valid width suffix, .w or .b was expected", suffix)))
+    };
+    Ok((get_instr(name)?, Some(width)))
+}
+
+/// Width of the address operand of zero page (8) and absolute (16) modes
+fn canonical_mode_len(mode: &AdrMode) -> Option<usize> {
+    match mode {
+        AdrMode::ZP | AdrMode::ZPX | AdrMode::ZPY => Some(8),
+        AdrMode::ABS | AdrMode::ABSX | AdrMode::ABSY => Some(16),
+        _ => None
     }
 }
 
+/// ASL, LSR, ROL and ROR can operate on the accumulator
+fn has_accumulator_mode(i: &Instr) -> bool {
+    OPCODES.contains_key(&(i.to_owned(), AdrMode::ACC))
+}
+
 fn is_branching(i: &Instr) -> bool {
     let list = [
         Instr::BPL, Instr::BMI, Instr::BVC,
         Instr::BVS, Instr::BCC, Instr::BCS,
-        Instr::BNE, Instr::BEQ
+        Instr::BNE, Instr::BEQ,
+        Instr::JPL, Instr::JMI, Instr::JVC,
+        Instr::JVS, Instr::JCC, Instr::JCS,
+        Instr::JNE, Instr::JEQ
     ];
     for item in list {
         if item.to_string().eq(&i.to_string()) {
@@ -109,26 +374,117 @@ fn is_branching(i: &Instr) -> bool {
 }
 
 
-pub struct AsmParser<'a> {
-    tokens: &'a Vec<Token>,
+const MAX_NESTING: usize = 128;
+
+/// Binary operators from the lowest to the highest precedence (C-like)
+const BINARY_OPERATORS: [&[Token]; 10] = [
+    &[Token::LOR],
+    &[Token::LAND],
+    &[Token::OR],
+    &[Token::XOR],
+    &[Token::AND],
+    &[Token::EQUAL, Token::NOTEQUAL],
+    &[Token::LT, Token::GT, Token::LTE, Token::GTE],
+    &[Token::SHL, Token::SHR],
+    &[Token::PLUS, Token::MINUS],
+    &[Token::MULT, Token::DIV, Token::MOD]
+];
+
+/// Negation, bitwise not, logical not, low byte and high byte
+const UNARY_OPERATORS: [Token; 5] = [Token::MINUS, Token::NOT, Token::LNOT, Token::LT, Token::GT];
+const MAX_REPEAT: usize = 0x10000;
+const MAX_UNROLLED_TOKENS: usize = 1 << 20;
+
+/// Tokens of the line `name = value`, located at `name`
+fn assignment(name: &Spanned<String>, value: i64) -> Vec<Spanned<Token>> {
+    let mut tokens = vec![Token::LITERAL(name.value.clone()), Token::EQUAL];
+    if value < 0 {
+        tokens.push(Token::MINUS);
+    }
+    tokens.push(Token::DEC(value.unsigned_abs().to_string()));
+    tokens.push(Token::NEWLINE);
+    tokens
+        .into_iter()
+        .map(|value| Spanned { value, span: name.span.clone() })
+        .collect()
+}
+
+/// .if block being parsed
+struct Conditional {
+    /// Location of the directive opening the block
+    span: Span,
+    /// Lines around the block are assembled
+    enclosing: bool,
+    /// One of the branches was assembled
+    taken: bool,
+    /// Lines of the current branch are assembled
+    active: bool,
+    /// .else was seen
+    closing: bool
+}
+
+pub struct AsmParser {
+    tokens: Vec<Spanned<Token>>,
     cursor: usize,
-    variables: HashMap<String, MathExpr> 
+    /// Depth of parenthesis in the current expression
+    nesting: usize,
+    variables: HashMap<String, MathExpr>,
+    /// Labels declared so far
+    labels: HashSet<String>,
+    conditionals: Vec<Conditional>,
+    /// Number of tokens produced by .repeat so far
+    unrolled: usize,
+    /// Number of anonymous labels declared so far
+    anonymous: usize,
+    /// Anonymous labels referred to, checked once all are declared
+    anonymous_refs: Vec<(usize, Span)>,
+    /// Macros and includes of the statements being assembled
+    preprocessor: AsmPreprocessor
 }
 
-impl<'a> AsmParser<'a> {
-    pub fn new(tokens: &'a Vec<Token>) -> Self {
+impl AsmParser {
+    pub fn new(tokens: &[Token]) -> Self {
+        Self::from_spanned(tokens
+            .iter()
+            .map(|tk| Spanned { value: tk.clone(), span: Span::default() })
+            .collect())
+    }
+
+    pub fn from_spanned(tokens: Vec<Spanned<Token>>) -> Self {
         Self {
             tokens,
             cursor: 0,
-            variables: HashMap::new()
+            nesting: 0,
+            variables: HashMap::new(),
+            labels: HashSet::new(),
+            conditionals: vec![],
+            unrolled: 0,
+            anonymous: 0,
+            anonymous_refs: vec![],
+            preprocessor: AsmPreprocessor::default()
         }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<Expr>, String> {
+    pub fn with_preprocessor(mut self, preprocessor: AsmPreprocessor) -> Self {
+        self.preprocessor = preprocessor;
+        self
+    }
+
+    pub fn parse(&mut self) -> Result<Vec<Expr>, AsmError> {
+        let prog = self.parse_spanned()?;
+        Ok(prog
+            .into_iter()
+            .map(|expr| expr.value)
+            .collect())
+    }
+
+    pub fn parse_spanned(&mut self) -> Result<Vec<Spanned<Expr>>, AsmError> {
         let mut prog = Vec::new();
         self.cursor = 0;
+        self.conditionals.clear();
+        self.anonymous = 0;
+        self.anonymous_refs.clear();
         loop {
-            // println!("{:?}", self.curr().clone());
             // cleanup
             if self.is_eof() {
                 break;
@@ -137,104 +493,442 @@ impl<'a> AsmParser<'a> {
                 self.next();
                 continue;
             }
-
-            // assign
-            if *self.peek_next() == Token::EQUAL {
-                match self.curr() {
-                    Token::LITERAL(_) => {
-                        prog.push(self.state_assign()?);
-                        continue;
-                    },
-                    _ => {
-                        return Err("assign expression expects a literal (lhs) / expression(rhs)".to_string())    
-                    }
+            if let Token::DIRECTIVE(name) = self.curr().clone() {
+                if is_conditional(&name) {
+                    self.state_conditional(&name)
+                        .map_err(|e| e.or_at(&self.curr_span()))?;
+                    continue;
+                }
+            }
+            if !self.is_active() {
+                self.skip_line();
+                continue;
+            }
+            let expanded = self.preprocessor
+                .expand_statement(&self.tokens, self.cursor)
+                .map_err(|e| e.or_at(&self.curr_span()))?;
+            if let Some((tokens, end)) = expanded {
+                self.tokens.splice(self.cursor..end, tokens);
+                continue;
+            }
+            if let Token::DIRECTIVE(name) = self.curr().clone() {
+                if is_directive(&name, &["repeat", "rept"]) {
+                    self.state_repeat()
+                        .map_err(|e| e.or_at(&self.curr_span()))?;
+                    continue;
                 }
             }
+            let start = self.cursor;
+            let expr = self
+                .state_line()
+                .map_err(|e| e.or_at(&self.curr_span()))?;
+            let span = self.span_from(start);
+            prog.push(Spanned { value: expr, span });
+        }
+        if let Some(block) = self.conditionals.last() {
+            return Err(AsmError::directive("conditional block is never closed by .endif".to_string())
+                .or_at(&block.span));
+        }
+        if let Some((_, span)) = self.anonymous_refs.iter().find(|(index, _)| *index >= self.anonymous) {
+            return Err(AsmError::symbol("no anonymous label follows".to_string()).or_at(span));
+        }
+        Ok(prog)
+    }
 
-            // label declaration
-            if *self.peek_next() == Token::COLON {
-                match self.curr() {
-                    Token::LITERAL(_) => {
-                        prog.push(self.state_label()?);
-                        continue;
-                    },
-                    _ => {
-                        return Err("label expression expects a literal (lhs) / expression(rhs)".to_string())    
-                    }
+    /// Lines are assembled unless a condition around them is false
+    fn is_active(&self) -> bool {
+        self.conditionals
+            .last()
+            .map(|block| block.enclosing && block.active)
+            .unwrap_or(true)
+    }
+
+    fn skip_line(&mut self) {
+        while !self.is_endline() && !self.is_eof() {
+            self.next();
+        }
+    }
+
+    /// .if EXPR | .ifdef SYMBOL | .ifndef SYMBOL \
+    /// .elseif EXPR | .else | .endif
+    fn state_conditional(&mut self, name: &str) -> Result<(), AsmError> {
+        let span = self.curr_span();
+        self.next();
+        match name.to_lowercase().as_str() {
+            "if" | "ifdef" | "ifndef" => {
+                let enclosing = self.is_active();
+                // conditions of a skipped block may refer to anything
+                let active = enclosing && self.consume_condition(name)?;
+                self.conditionals.push(Conditional {
+                    span,
+                    enclosing,
+                    taken: active,
+                    active,
+                    closing: false
+                });
+            },
+            "elseif" => {
+                let block = self.conditionals
+                    .last()
+                    .ok_or_else(|| AsmError::directive(".elseif without a matching .if".to_string()))?;
+                if block.closing {
+                    return Err(AsmError::directive(".elseif after .else".to_string()));
+                }
+                let active = block.enclosing && !block.taken && self.consume_condition("if")?;
+                let block = self.conditionals.last_mut().unwrap();
+                block.active = active;
+                block.taken |= active;
+            },
+            "else" => {
+                let block = self.conditionals
+                    .last_mut()
+                    .ok_or_else(|| AsmError::directive(".else without a matching .if".to_string()))?;
+                if block.closing {
+                    return Err(AsmError::directive(".else is repeated".to_string()));
+                }
+                block.closing = true;
+                block.active = !block.taken;
+                block.taken = true;
+            },
+            _ => {
+                // endif
+                if self.conditionals.pop().is_none() {
+                    return Err(AsmError::directive(".endif without a matching .if".to_string()));
                 }
             }
+        }
+        if !self.is_active() || self.is_endline() || self.is_eof() || self.is_comment() {
+            self.skip_line();
+            return Ok(());
+        }
+        Err(self.curr_unexpected())
+    }
 
-            // directive and comment
-            match self.curr().clone() {
-                Token::DIRECTIVE(name) => {
-                    match name.as_str() {
-                        "byte" | "BYTE" | "db" | "DB" => {
-                            self.next();
-                            let seq = self.consume_sequence(8)?;
-                            prog.push(Expr::DIRECTIVE(Directive::BYTE(seq)));
-                        },
-                        "dword" | "DWORD" | "dw" | "DW" => {
-                            self.next();
-                            let seq = self.consume_sequence(16)?;
-                            prog.push(Expr::DIRECTIVE(Directive::DWORD(seq)));
-                        },
-                        "segment" => {
-                            self.next();
-                            let segname: String = self.consume_string_and_lift()?;
-                            prog.push(Expr::DIRECTIVE(Directive::SEGMENT(segname)));
-                        },
-                        "proc" => {
-                            self.next();
-                            let procname: String = self.consume_literal_and_lift()?;
-                            prog.push(Expr::DIRECTIVE(Directive::PROC(procname)));
-                        },
-                        "endproc" => {
-                            self.next();
-                            prog.push(Expr::DIRECTIVE(Directive::ENDPROC));
-                        },
-                        "res" => {
-                            self.next();
-                            match self.curr() {
-                                Token::DEC(n) => {
-                                    let size = usize::from_str_radix(&n, 10).unwrap();
-                                    self.next();
-                                    prog.push(Expr::DIRECTIVE(Directive::RESERVE(size)));
-                                },
-                                tk => {
-                                    return Err(format!("decimal number was expected, got {:?}", tk));
-                                }
-                            }
-                        },
-                        _ => {
-                            return Err(self.curr_unexpected());
-                        }
-                    }
-                    continue;
+    /// .repeat COUNT[, COUNTER] \
+    /// body \
+    /// .endrepeat
+    ///
+    /// The block is replaced in place by COUNT copies of its body,
+    /// each one preceded by `COUNTER = index`. A COUNTER already
+    /// assigned a known value gets it back after the block, one
+    /// depending on a label or on `*` cannot be a COUNTER
+    fn state_repeat(&mut self) -> Result<(), AsmError> {
+        let start = self.cursor;
+        let span = self.curr_span();
+        self.next();
+        let count = self.consume_constant("repeat count")?;
+        if count > MAX_REPEAT {
+            return Err(AsmError::range(format!("repeat count {} is above {}", count, MAX_REPEAT)));
+        }
+        let mut counter = None;
+        if *self.curr() == Token::COMMA {
+            self.consume(Token::COMMA)?;
+            let span = self.curr_span();
+            counter = Some(Spanned { value: self.consume_literal_and_lift()?, span });
+        }
+        let restored = match &counter {
+            Some(name) => match self.variables.get(&name.value) {
+                Some(value) if self.is_deferred(value) => {
+                    return Err(AsmError::symbol(format!(
+                        "repeat counter {} would lose its value {}",
+                        name.value, value
+                    )).or_at(&name.span));
                 },
-                Token::COMMENT(..) => {
-                    self.next();
-                    continue;
+                Some(value) => Some(self.eval_math(value)?.value),
+                None => None
+            },
+            None => None
+        };
+        if !self.is_endline() && !self.is_comment() {
+            return Err(self.curr_unexpected());
+        }
+
+        let body_start = self.cursor;
+        let mut depth = 0;
+        loop {
+            match self.curr() {
+                Token::EOF => {
+                    return Err(AsmError::directive("repeat block is never closed by .endrepeat".to_string())
+                        .or_at(&span));
+                },
+                Token::DIRECTIVE(name) if is_directive(name, &["repeat", "rept"]) => depth += 1,
+                Token::DIRECTIVE(name) if is_directive(name, &["endrepeat", "endrep"]) => {
+                    if depth == 0 {
+                        break;
+                    }
+                    depth -= 1;
                 },
                 _ => {}
             }
-            // instruction
-            prog.push(self.state_instr()?);
             self.next();
         }
-        Ok(prog)
+        let body = &self.tokens[body_start..self.cursor];
+        self.unrolled += body.len() * count;
+        if self.unrolled > MAX_UNROLLED_TOKENS {
+            return Err(AsmError::range(format!("repeat blocks unroll to more than {} tokens", MAX_UNROLLED_TOKENS))
+                .or_at(&span));
+        }
+
+        let mut unrolled = Vec::with_capacity(body.len() * count);
+        for index in 0..count {
+            if let Some(counter) = &counter {
+                unrolled.extend(assignment(counter, index as i64));
+            }
+            unrolled.extend_from_slice(body);
+        }
+        if let (Some(counter), Some(value)) = (&counter, restored) {
+            unrolled.extend(assignment(counter, value));
+        }
+        // .endrepeat is dropped, the rest of its line is kept
+        self.tokens.splice(start..=self.cursor, unrolled);
+        self.cursor = start;
+        Ok(())
+    }
+
+    /// Evaluate the condition of `.if`, `.ifdef` or `.ifndef`
+    fn consume_condition(&mut self, name: &str) -> Result<bool, AsmError> {
+        match name.to_lowercase().as_str() {
+            "ifdef" | "ifndef" => {
+                let symbol = self.consume_literal_and_lift()?;
+                let defined = self.variables.contains_key(&symbol) || self.labels.contains(&symbol);
+                Ok(defined == (name.eq_ignore_ascii_case("ifdef")))
+            },
+            _ => {
+                let expr = self.consume_math_expr()?;
+                if self.is_deferred(&expr) {
+                    return Err(AsmError::directive(format!("condition {} must be known before use", expr)));
+                }
+                Ok(self.eval_math(&expr)?.value != 0)
+            }
+        }
+    }
+
+    fn state_line(&mut self) -> Result<Expr, AsmError> {
+        // assign
+        if *self.peek_next() == Token::EQUAL {
+            match self.curr() {
+                Token::LITERAL(_) => {
+                    return self.state_assign();
+                },
+                _ => {
+                    return Err(AsmError::parse("assign expression expects a literal (lhs) / expression(rhs)".to_string()))    
+                }
+            }
+        }
+
+        // anonymous label, referred to as :+ or :-
+        if *self.curr() == Token::COLON {
+            self.next();
+            let name = format!(":{}", self.anonymous);
+            self.anonymous += 1;
+            return Ok(Expr::LABEL(name));
+        }
+
+        // label declaration, .name: is a local label
+        // (BNE :+ is a reference to an anonymous label)
+        let is_reference = matches!(self.peek_at(2), Token::PLUS | Token::MINUS);
+        if *self.peek_next() == Token::COLON && !is_reference {
+            match self.curr() {
+                Token::LITERAL(_) | Token::DIRECTIVE(_) => {
+                    return self.state_label();
+                },
+                _ => {
+                    return Err(AsmError::parse("label expression expects a literal (lhs) / expression(rhs)".to_string()))    
+                }
+            }
+        }
+
+        // directive
+        if let Token::DIRECTIVE(name) = self.curr().clone() {
+            return self.state_directive(&name);
+        }
+
+        // instruction
+        let instr = self.state_instr()?;
+        if !self.is_endline() && !self.is_eof() && !self.is_comment() {
+            return Err(self.curr_unexpected());
+        }
+        Ok(instr)
+    }
+
+    fn state_directive(&mut self, name: &str) -> Result<Expr, AsmError> {
+        let directive = match name.to_lowercase().as_str() {
+            "byte" | "db" => {
+                self.next();
+                let seq = self.consume_sequence(8)?;
+                Directive::BYTE(seq)
+            },
+            "dword" | "dw" | "word" => {
+                self.next();
+                let seq = self.consume_sequence(16)?;
+                Directive::DWORD(seq)
+            },
+            "lobytes" => {
+                self.next();
+                Directive::LOBYTES(self.consume_sequence(16)?)
+            },
+            "hibytes" => {
+                self.next();
+                Directive::HIBYTES(self.consume_sequence(16)?)
+            },
+            "dbyt" => {
+                self.next();
+                Directive::DBYT(self.consume_sequence(16)?)
+            },
+            "faraddr" => {
+                self.next();
+                Directive::FARADDR(self.consume_sequence(24)?)
+            },
+            "segment" => {
+                self.next();
+                let segname: String = self.consume_string_and_lift()?;
+                Directive::SEGMENT(segname)
+            },
+            "proc" => {
+                self.next();
+                let procname: String = self.consume_literal_and_lift()?;
+                self.labels.insert(procname.clone());
+                Directive::PROC(procname)
+            },
+            "endproc" => {
+                self.next();
+                Directive::ENDPROC
+            },
+            "scope" => {
+                self.next();
+                match self.curr() {
+                    Token::LITERAL(_) => Directive::SCOPE(Some(self.consume_literal_and_lift()?)),
+                    _ => Directive::SCOPE(None)
+                }
+            },
+            "endscope" => {
+                self.next();
+                Directive::ENDSCOPE
+            },
+            "res" => {
+                self.next();
+                let size = self.consume_math_expr()?;
+                let size = if self.is_deferred(&size) {
+                    self.fold_variables(&size)?
+                } else {
+                    MathExpr::NUM(self.eval_math(&size)?)
+                };
+                let fill = self.consume_fill_byte(false)?;
+                Directive::RESERVE(size, fill)
+            },
+            "align" => {
+                self.next();
+                let alignment = self.consume_constant("alignment")?;
+                if alignment == 0 {
+                    return Err(AsmError::range("alignment must be at least 1".to_string()));
+                }
+                let fill = self.consume_fill_byte(false)?;
+                Directive::ALIGN(alignment, fill)
+            },
+            "fill" => {
+                self.next();
+                let count = self.consume_constant("count")?;
+                let fill = self.consume_fill_byte(true)?;
+                Directive::FILL(count, fill)
+            },
+            "pad" => {
+                self.next();
+                let address = self.consume_constant("address")?;
+                if address > 0xffff {
+                    return Err(AsmError::range(format!("address {} is out of the address space", address)));
+                }
+                let fill = self.consume_fill_byte(false)?;
+                Directive::PAD(address, fill)
+            },
+            "org" => {
+                self.next();
+                let expr = self.consume_math_expr()?;
+                if self.is_deferred(&expr) {
+                    return Err(AsmError::directive(format!("origin {} must be known before use", expr)));
+                }
+                let origin = self.eval_math(&expr)?;
+                if !origin.fits(16, false) {
+                    return Err(AsmError::range(format!("origin {} is out of the address space", origin.value)));
+                }
+                Directive::ORG(origin.value as usize)
+            },
+            "endrepeat" | "endrep" => {
+                return Err(AsmError::directive(".endrepeat without a matching .repeat".to_string()));
+            },
+            "illegal" => {
+                self.next();
+                let allowed = match self.curr() {
+                    Token::LITERAL(s) if s.eq_ignore_ascii_case("on") => true,
+                    Token::LITERAL(s) if s.eq_ignore_ascii_case("off") => false,
+                    tk => return Err(AsmError::parse(format!("on or off was expected, got {:?}", tk)))
+                };
+                self.next();
+                Directive::ILLEGAL(allowed)
+            },
+            "incbin" => {
+                self.next();
+                let path = self.consume_string_and_lift()?;
+                let mut offset = 0;
+                let mut length = None;
+                if *self.curr() == Token::COMMA {
+                    self.consume(Token::COMMA)?;
+                    offset = self.consume_constant("offset")?;
+                    if *self.curr() == Token::COMMA {
+                        self.consume(Token::COMMA)?;
+                        length = Some(self.consume_constant("length")?);
+                    }
+                }
+                Directive::INCBIN(path, offset, length)
+            },
+            _ => {
+                return Err(self.curr_unexpected());
+            }
+        };
+        Ok(Expr::DIRECTIVE(directive))
     }
 
     fn curr(&self) -> &Token {
         self
             .tokens
             .get(self.cursor)
+            .map(|tk| &tk.value)
             .unwrap_or(&Token::EOF)
     }
 
+    fn curr_span(&self) -> Span {
+        self
+            .tokens
+            .get(self.cursor)
+            .or(self.tokens.last())
+            .map(|tk| tk.span.clone())
+            .unwrap_or_default()
+    }
+
+    /// Span from the token at `start` up to the last token consumed,
+    /// the end of the line is not included
+    fn span_from(&self, start: usize) -> Span {
+        let mut end = self.cursor.max(start + 1) - 1;
+        while end > start {
+            match self.tokens[end].value {
+                Token::NEWLINE | Token::COMMENT(..) | Token::EOF => end -= 1,
+                _ => break
+            }
+        }
+        match (self.tokens.get(start), self.tokens.get(end)) {
+            (Some(first), Some(last)) => first.span.to(&last.span),
+            _ => self.curr_span()
+        }
+    }
+
     fn peek_next(&self) -> &Token {
+        self.peek_at(1)
+    }
+
+    fn peek_at(&self, offset: usize) -> &Token {
         self
             .tokens
-            .get(self.cursor + 1)
+            .get(self.cursor + offset)
+            .map(|tk| &tk.value)
             .unwrap_or(&Token::EOF)
     }
 
@@ -247,113 +941,109 @@ impl<'a> AsmParser<'a> {
     }
 
     fn is_comment(&self) -> bool {
-        match *self.curr() {
-            Token::COMMENT(..) => true,
-            _ => false
-        }
+        matches!(*self.curr(), Token::COMMENT(..))
     }
 
     fn next(&mut self) -> &Token {
         self.cursor = min(self.tokens.len(), self.cursor + 1);
-        return self.curr();
+        self.curr()
     }
 
-    fn curr_unexpected(&self) -> String {
-        format!("token {:?} unexpected", self.curr())
+    fn curr_unexpected(&self) -> AsmError {
+        AsmError::parse(format!("token {:?} unexpected", self.curr()))
     }
 
-    fn consume(&mut self, token: Token) -> Result<Token, String> {
+    fn consume(&mut self, token: Token) -> Result<Token, AsmError> {
         if *self.curr() == token {
             self.next();
             return Ok(token);
         }
-        Err(format!("{:?} was expected, got {:?} instead", token, *self.curr()))
+        Err(AsmError::parse(format!("{:?} was expected, got {:?} instead", token, *self.curr())))
     }
 
-    fn consume_literal_and_lift(&mut self) -> Result<String, String> {
+    fn consume_literal_and_lift(&mut self) -> Result<String, AsmError> {
         let curr = self.curr().clone();
         match curr {
             Token::LITERAL(lit) => {
                 self.next();
                 Ok(lit)
             },
-            token => Err(format!("literal was expected, got {:?} instead",token))
+            token => Err(AsmError::parse(format!("literal was expected, got {:?} instead",token)))
         }
     }
 
-    fn consume_string_and_lift(&mut self) -> Result<String, String> {
+    fn consume_string_and_lift(&mut self) -> Result<String, AsmError> {
         let curr = self.curr().clone();
         match curr {
             Token::STR(s) => {
                 self.next();
                 Ok(s)
             },
-            token => Err(format!("string was expected, got {:?} instead", token))
+            token => Err(AsmError::parse(format!("string was expected, got {:?} instead", token)))
         }
     }
 
-    fn consume_literal(&mut self, s: &str) -> Result<Token, String> {
+    fn consume_literal(&mut self, s: &str) -> Result<Token, AsmError> {
         let curr = self.curr().clone();
         match &curr {
             Token::LITERAL(lit) => {
-                if lit.ne(s) {
-                    return Err(format!("literal {:?} was expected, got {:?} instead", s, curr))
+                if !lit.eq_ignore_ascii_case(s) {
+                    return Err(AsmError::parse(format!("literal {:?} was expected, got {:?} instead", s, curr)))
                 }
                 let curr = self.curr().clone();
                 self.next();
                 Ok(curr)
             },
-            token => Err(format!("literal {:?} was expected, got {:?} instead", s, token))
+            token => Err(AsmError::parse(format!("literal {:?} was expected, got {:?} instead", s, token)))
         }
     }
 
-    fn consume_sequence(&mut self, size: usize) -> Result<Vec<NumericValue>, String>  {
-        if size != 8 && size != 16 {
-            return Err(format!("size must be 8 or 16, {} was given", size));
+    fn consume_sequence(&mut self, size: usize) -> Result<Vec<Operand>, AsmError>  {
+        if size != 8 && size != 16 && size != 24 {
+            return Err(AsmError::parse(format!("size must be 8, 16 or 24, {} was given", size)));
         }
-        let mut seq: Vec<NumericValue> = vec![];
+        let mut seq: Vec<Operand> = vec![];
         while !self.is_eof() && !self.is_endline() && !self.is_comment() {
             match self.curr() {
-                Token::STR(s) => {
+                Token::STR(s) if size <= 16 => {
+                    let list: Vec<char> = s.chars().collect();
+                    if let Some(ch) = list.iter().find(|ch| **ch as u32 > 0xff) {
+                        return Err(AsmError::range(format!("character {:?} does not fit in 8 bits", ch)));
+                    }
                     if size == 16 {
                         // consume per block of 2 chars
-                        if s.len() % 2 != 0 {
-                            return Err(format!("length of {:?} must be a multiple of 2 to form a 2 byte word", s));
+                        if !list.len().is_multiple_of(2) {
+                            return Err(AsmError::parse(format!("length of {:?} must be a multiple of 2 to form a 2 byte word", s)));
                         }
-                        let list: Vec<char> = s.chars().collect();
                         let mut pos = 0;
                         while pos < list.len() {
-                            let hi = list[pos] as u16;
-                            let lo = list[pos + 1] as u16;
+                            let hi = list[pos] as i64;
+                            let lo = list[pos + 1] as i64;
                             let value = (hi << 8) | lo;
-                            seq.push(NumericValue {value, size});
+                            seq.push(Operand::VALUE(NumericValue {value, size}));
                             pos += 2;
                         }
                     } else {
                         // == 8
-                        for ch in s.chars() {
-                            let value = ch as u16;
-                            seq.push(NumericValue { value, size: 8 });
+                        for ch in list {
+                            let value = ch as i64;
+                            seq.push(Operand::VALUE(NumericValue { value, size: 8 }));
                         }
                     }
                     self.next();
                 },
                 _ => {
                     // Note: char is also a valid math operand
-                    let expr = self.consume_math_expr()?;
-                    let mut value = self.eval_math(&expr)?;
-                    if value.size > size {
-                        let pos = seq.len();
-                        return Err(format!(
-                            "{}-th value is {} bytes, {} was expected", 
-                            pos, 
-                            max(1, value.size / 8), 
-                            size / 8
-                        ));
-                    }
-                    // promote for values if given size is bigger
-                    value.size = max(value.size, size);
-                    seq.push(value);
+                    // labels are resolved by the compiler
+                    let item = match self.consume_operand()? {
+                        Operand::VALUE(mut value) => {
+                            // range is checked by the compiler, promote for values if given size is bigger
+                            value.size = max(value.size, size);
+                            Operand::VALUE(value)
+                        },
+                        operand => operand
+                    };
+                    seq.push(item);
                 }
             }
 
@@ -365,59 +1055,78 @@ impl<'a> AsmParser<'a> {
         Ok(seq)
     }
 
-    // expr      ::= term (+| -) expr | term
-    fn consume_math_expr(&mut self) -> Result<MathExpr, String> {
-        let expr = self.consume_math_term()?;
-        let bin = vec![Token::PLUS, Token::MINUS];
-        for op in bin {
-            if *self.curr() == op {
-                let op_token = self.consume(op)?;
-                let right = self.consume_math_expr()?;
-                return Ok(MathExpr::BIN(op_token, Box::new(expr), Box::new(right)));
-            }
-        }
-        Ok(expr)
+    // expr      ::= binary(0)
+    fn consume_math_expr(&mut self) -> Result<MathExpr, AsmError> {
+        self.consume_math_binary(0)
     }
 
-    fn try_expand_math(&mut self) -> Result<NumericValue, String> {
-        match self.consume_math_expr() {
-            Ok(expr) => self.eval_math(&expr),
-            Err(_) => {
-                let ret = canonicalize_number(self.curr())?;
-                self.next();
-                Ok(ret)
+    // binary(n) ::= unary (op binary(level(op) + 1))*    with level(op) >= n
+    // operators of the same level are left-associative: 10 - 3 - 2 = (10 - 3) - 2
+    fn consume_math_binary(&mut self, min_level: usize) -> Result<MathExpr, AsmError> {
+        let mut expr = self.consume_math_unary()?;
+        let nesting = self.nesting;
+        while let Some(level) = binary_level(self.curr()) {
+            if level < min_level {
+                break;
+            }
+            // each operation nests the expression a bit deeper
+            if self.nesting >= MAX_NESTING {
+                self.nesting = nesting;
+                return Err(AsmError::parse(format!("more than {} nested operations", MAX_NESTING)));
             }
+            self.nesting += 1;
+            let op_token = self.consume(self.curr().clone())?;
+            let right = self.consume_math_binary(level + 1);
+            let right = right.inspect_err(|_| self.nesting = nesting)?;
+            expr = MathExpr::BIN(op_token, Box::new(expr), Box::new(right));
         }
+        self.nesting = nesting;
+        Ok(expr)
     }
 
-    // term      ::= factor (* | /) term | factor
-    fn consume_math_term(&mut self) -> Result<MathExpr, String> {
-        let expr = self.consume_math_factor()?;
-        let bin = vec![Token::MULT, Token::DIV];
-        for op in bin {
-            if *self.curr() == op {
-                let op_token = self.consume(op)?;
-                let right = self.consume_math_term()?;
-                return Ok(MathExpr::BIN(op_token, Box::new(expr), Box::new(right)));
-            }
+    // unary     ::= (- | ~ | ! | < | >) unary | factor
+    fn consume_math_unary(&mut self) -> Result<MathExpr, AsmError> {
+        if !UNARY_OPERATORS.contains(self.curr()) {
+            return self.consume_math_factor();
         }
-        Ok(expr)
+        if self.nesting >= MAX_NESTING {
+            return Err(AsmError::parse(format!("more than {} nested unary operators", MAX_NESTING)));
+        }
+        let op_token = self.consume(self.curr().clone())?;
+        self.nesting += 1;
+        let expr = self.consume_math_unary();
+        self.nesting -= 1;
+        Ok(MathExpr::UNARY(op_token, Box::new(expr?)))
     }
 
-    // factor    ::= (expr) | unary
-    fn consume_math_factor(&mut self) -> Result<MathExpr, String> {
+    // factor    ::= (expr) | atom
+    fn consume_math_factor(&mut self) -> Result<MathExpr, AsmError> {
         if *self.curr() == Token::PARENTOPEN {
+            if self.nesting >= MAX_NESTING {
+                return Err(AsmError::parse(format!("more than {} nested parenthesis", MAX_NESTING)));
+            }
             self.consume(Token::PARENTOPEN)?;
-            let expr = self.consume_math_expr()?;
+            self.nesting += 1;
+            let expr = self.consume_math_expr();
+            self.nesting -= 1;
+            let expr = expr?;
             self.consume(Token::PARENTCLOSE)?;
             return Ok(expr);
         }
-        self.consume_math_unary()
+        self.consume_math_atom()
     }
 
-    // unary     ::= <literal> | hex | dec | bin
-    fn consume_math_unary(&mut self) -> Result<MathExpr, String> {
-        match canonicalize_number(&self.curr()) {
+    // atom      ::= <literal> | .<literal> | hex | dec | bin | char | * | anonymous
+    fn consume_math_atom(&mut self) -> Result<MathExpr, AsmError> {
+        if *self.curr() == Token::COLON {
+            return self.consume_anonymous_ref();
+        }
+        // '*' where an operand is expected is the program counter
+        if *self.curr() == Token::MULT {
+            self.next();
+            return Ok(MathExpr::PC);
+        }
+        match canonicalize_number(self.curr()) {
             Ok(number) => {
                 self.next();
                 Ok(MathExpr::NUM(number))
@@ -429,6 +1138,12 @@ impl<'a> AsmParser<'a> {
                         self.next();
                         Ok(out)
                     },
+                    // local label
+                    Token::DIRECTIVE(s) => {
+                        let out = MathExpr::PLACEHOLDER(format!(".{}", s));
+                        self.next();
+                        Ok(out)
+                    },
                     _ => {
                         Err(e)
                     }
@@ -437,197 +1152,322 @@ impl<'a> AsmParser<'a> {
         }
     }
 
+    // anonymous ::= ':' '+'+ | ':' '-'+
+    // :+ is the next anonymous label, :++ the one after, :- the previous one, ...
+    fn consume_anonymous_ref(&mut self) -> Result<MathExpr, AsmError> {
+        let start = self.cursor;
+        self.consume(Token::COLON)?;
+        let sign = self.curr().clone();
+        if sign != Token::PLUS && sign != Token::MINUS {
+            return Err(AsmError::parse(format!("anonymous label reference :+ or :- was expected, got {:?}", sign)));
+        }
+        // the signs must stick to each other, ':+ +1' is ':+' plus 1
+        let mut count = 0;
+        while *self.curr() == sign && self.tokens[self.cursor - 1].span.end == self.curr_span().start {
+            count += 1;
+            self.next();
+        }
+        if count == 0 {
+            return Err(AsmError::parse("anonymous label reference cannot contain spaces".to_string()));
+        }
+        let span = self.span_from(start);
+        let index = if sign == Token::PLUS {
+            self.anonymous + count - 1
+        } else {
+            match self.anonymous.checked_sub(count) {
+                Some(index) => index,
+                None => return Err(AsmError::symbol("no anonymous label precedes".to_string()).or_at(&span))
+            }
+        };
+        self.anonymous_refs.push((index, span));
+        Ok(MathExpr::PLACEHOLDER(format!(":{}", index)))
+    }
+
     // expr should guarantee to be not recursive
-    pub fn eval_math(&self, expr: &MathExpr) -> Result<NumericValue, String> {
+    pub fn eval_math(&self, expr: &MathExpr) -> Result<NumericValue, AsmError> {
+        eval_math_expr(expr, &|s: &str| {
+            match self.variables.get(s) {
+                Some(nested) => self.eval_math(nested),
+                None => Err(AsmError::undefined(s))
+            }
+        })
+    }
+
+    /// `expr` with the variables known at this point replaced by their value,
+    /// assigning them again later does not change a deferred expression
+    pub fn fold_variables(&self, expr: &MathExpr) -> Result<MathExpr, AsmError> {
         match expr {
-            MathExpr::BIN(op, lvalue, rvalue) => {
-                let left = self.eval_math(&lvalue)?;
-                let right = self.eval_math(&rvalue)?;
-                let value = match op {
-                    Token::PLUS => {
-                        if left.value.checked_add(right.value).is_none() {
-                            return Err(format!("add overflow: left {}, right {}", left.value, right.value));
-                        }
-                        Ok(left.value + right.value)
-                    },
-                    Token::MULT => {
-                        if left.value.checked_mul(right.value).is_none() {
-                            return Err(format!("multiplication overflow: left {}, right {}", left.value, right.value));
-                        }
-                        Ok(left.value * right.value)
-                    },
-                    Token::MINUS => {
-                        if left.value.checked_sub(right.value).is_none() {
-                            return Err(format!("substraction overflow: left {}, right {}", left.value, right.value));
-                        }
-                        Ok(left.value - right.value)
-                    },
-                    Token::DIV => {
-                        if left.value.checked_div(right.value).is_none() {
-                            return Err(format!("cannot divide {} by zero", left.value));
-                        }
-                        Ok(left.value / right.value)
-                    }
-                    token => Err(format!("binary operator {:?} not implemented", token))
-                }?;
-                Ok(NumericValue { value, size: max(left.size, right.size)})
+            MathExpr::PLACEHOLDER(name) => match self.variables.get(name) {
+                Some(value) if !self.is_deferred(value) => Ok(MathExpr::NUM(self.eval_math(value)?)),
+                _ => Ok(expr.clone())
             },
-            MathExpr::NUM(n) => Ok(n.clone()),
+            MathExpr::BIN(op, lvalue, rvalue) => Ok(MathExpr::BIN(
+                op.clone(),
+                Box::new(self.fold_variables(lvalue)?),
+                Box::new(self.fold_variables(rvalue)?)
+            )),
+            MathExpr::UNARY(op, value) => Ok(MathExpr::UNARY(op.clone(), Box::new(self.fold_variables(value)?))),
+            MathExpr::NUM(_) | MathExpr::PC => Ok(expr.clone())
+        }
+    }
+
+    /// Check if `expr` refers to a symbol not known yet (e.g. a label),
+    /// such expression can only be evaluated by the compiler
+    pub fn is_deferred(&self, expr: &MathExpr) -> bool {
+        match expr {
+            MathExpr::NUM(_) => false,
+            // only known while compiling
+            MathExpr::PC => true,
+            MathExpr::BIN(_, lvalue, rvalue) => {
+                self.is_deferred(lvalue) || self.is_deferred(rvalue)
+            },
+            MathExpr::UNARY(_, value) => self.is_deferred(value),
             MathExpr::PLACEHOLDER(s) => {
-                let nested = self.variables.get(s);
-                if nested.is_some() {
-                    return self.eval_math(nested.unwrap());
+                match self.variables.get(s) {
+                    Some(nested) => self.is_deferred(nested),
+                    None => true
                 }
-                Err(format!("variable {:?} is undefined", s))
-            },
+            }
         }
     }
 
-    pub fn validate_factors(&self, expr: &MathExpr, assignee: &Option<String>) -> Result<bool, String> {
+    pub fn validate_factors(&self, expr: &MathExpr, assignee: &Option<String>) -> Result<bool, AsmError> {
         match expr {
-            MathExpr::NUM(_) => Ok(true),
+            MathExpr::NUM(_) | MathExpr::PC => Ok(true),
             MathExpr::BIN(_, lvalue, rvalue) => {
-                let left = self.validate_factors(&lvalue, assignee)?;
-                let right = self.validate_factors(&rvalue, assignee)?;
+                let left = self.validate_factors(lvalue, assignee)?;
+                let right = self.validate_factors(rvalue, assignee)?;
                 Ok(left && right)
             },
+            MathExpr::UNARY(_, value) => self.validate_factors(value, assignee),
             MathExpr::PLACEHOLDER(s) => {
-                if assignee.is_some() && s.to_owned() == assignee.clone().unwrap() {
-                    return Err(format!("variable {:?} has recursive definition", s))
+                if assignee.as_ref() == Some(s) {
+                    return Err(AsmError::symbol(format!("variable {:?} has recursive definition", s)))
                 }
-                let nested = self.variables.get(s);
-                if nested.is_some() {
-                    return self.validate_factors(nested.unwrap(), assignee);
+                match self.variables.get(s) {
+                    Some(nested) => self.validate_factors(nested, assignee),
+                    // can be a label, the compiler will tell
+                    None => Ok(true)
                 }
-                Err(format!("variable {:?} is undefined", s))
             },
         }
     }
 
-    fn state_assign(&mut self) -> Result<Expr, String> {
+    pub fn variables(&self) -> &HashMap<String, MathExpr> {
+        &self.variables
+    }
+
+    fn state_assign(&mut self) -> Result<Expr, AsmError> {
         let symbol = self.consume_literal_and_lift()?;
         self.consume(Token::EQUAL)?;
         let number = self.consume_math_expr()?;
         if !self.validate_factors(&number, &Some(symbol.clone()))? {
-            return Err(format!("{} rhs is not valid", symbol));
+            return Err(AsmError::parse(format!("{} rhs is not valid", symbol)));
         }
+        let number = if self.is_deferred(&number) {
+            self.fold_variables(&number)?
+        } else {
+            number
+        };
         self.variables.insert(symbol.clone(), number.clone());
         Ok(Expr::ASSIGN(symbol, number))
     }
 
-    fn state_label(&mut self) -> Result<Expr, String> {
-        let name = self.consume_literal_and_lift()?;
+    fn state_label(&mut self) -> Result<Expr, AsmError> {
+        let name = match self.curr().clone() {
+            Token::DIRECTIVE(local) => {
+                self.next();
+                format!(".{}", local)
+            },
+            _ => self.consume_literal_and_lift()?
+        };
         self.consume(Token::COLON)?;
+        self.labels.insert(name.clone());
         Ok(Expr::LABEL(name))
     }
 
+    /// Consume a math expression that must be known at parse time
+    fn consume_constant(&mut self, what: &str) -> Result<usize, AsmError> {
+        let expr = self.consume_math_expr()?;
+        if self.is_deferred(&expr) {
+            return Err(AsmError::directive(format!("{} {} must be known before use", what, expr)));
+        }
+        let number = self.eval_math(&expr)?;
+        usize::try_from(number.value)
+            .map_err(|_| AsmError::range(format!("{} {} cannot be negative", what, number.value)))
+    }
+
+    /// Follow the grammar \
+    /// fill ::= ',' expr    (optional unless `required`, 0 by default)
+    fn consume_fill_byte(&mut self, required: bool) -> Result<u8, AsmError> {
+        if !required && *self.curr() != Token::COMMA {
+            return Ok(0);
+        }
+        self.consume(Token::COMMA)?;
+        let expr = self.consume_math_expr()?;
+        if self.is_deferred(&expr) {
+            return Err(AsmError::directive(format!("fill value {} must be known before use", expr)));
+        }
+        let value = self.eval_math(&expr)?;
+        if !value.fits(8, true) {
+            return Err(AsmError::range(format!("fill value {} does not fit in 8 bits", value.value)));
+        }
+        Ok(value.value as u8)
+    }
+
+    /// Consume a math expression, evaluate it now if possible
+    /// or defer it to the compiler otherwise
+    fn consume_operand(&mut self) -> Result<Operand, AsmError> {
+        let expr = self.consume_math_expr()?;
+        if self.is_deferred(&expr) {
+            return Ok(Operand::EXPR(self.fold_variables(&expr)?));
+        }
+        let number = self.eval_math(&expr)?;
+        // only the digits of a literal tell its width, symbols are sized by value
+        if expr.has_symbol() {
+            return Ok(Operand::VALUE(NumericValue::sized(number.value)));
+        }
+        Ok(Operand::VALUE(number))
+    }
+
     /// Follow the grammar \
     /// [none ::= implied, accumulator] \
-    /// operand ::= none | imm | abs | ind | rel | zp \
+    /// operand ::= none | acc | imm | abs | ind | rel | zp \
+    /// acc     ::= 'a'                                  (context bound: only for ASL, LSR, ROL, ROR) \
     /// imm     ::= #$BB\
     /// ind     ::= '(' $LLHH ')' | '(' $BB ',' 'x' ')' | '(' $BB  ')' ',' 'y' \
-    /// rel     ::= $BB                                  (context bound: only for jumps BXX) \
-    /// zp      ::= $BB | $BB ',' ('x'|'y') \
-    /// abs     ::= $LLHH | $LLHH ',' ('x'|'y') \
-    fn state_instr(&mut self) -> Result<Expr, String> {
-        let instr = match self.curr().clone() {
-            Token::LITERAL(i) => Ok(get_instr(&i)?),
-            token => Err(format!("{:?} is not a literal", token))
-        }?;
+    /// rel     ::= $LLHH                                (context bound: only for jumps BXX) \
+    /// zp      ::= ['z:'] $BB | ['z:'] $BB ',' ('x'|'y') \
+    /// abs     ::= ['a:'] $LLHH | ['a:'] $LLHH ',' ('x'|'y') \
+    /// 
+    /// Any $BB or $LLHH can be an expression refering to labels, in that case
+    /// the operand is resolved by the compiler, abs is assumed until the compiler
+    /// finds that it fits in zp. \
+    /// The `a:` / `z:` prefixes and the `.w` / `.b` suffixes (LDA.w) override that choice
+    fn state_instr(&mut self) -> Result<Expr, AsmError> {
+        let (instr, width) = match self.curr().clone() {
+            Token::LITERAL(i) => split_width_suffix(&i)?,
+            token => return Err(AsmError::parse(format!("{:?} is not a literal", token)))
+        };
         self.next();
+        let instr = self.consume_instr_operand(instr, width)?;
+        if let (Some(width), Expr::INSTR(name, mode, _, _)) = (width, &instr) {
+            if canonical_mode_len(mode) != Some(width) {
+                return Err(AsmError::parse(format!("{} bits width cannot apply to {} in mode {:?}", width, name, mode)));
+            }
+        }
+        Ok(instr)
+    }
+
+    fn consume_instr_operand(&mut self, instr: Instr, suffix: Option<usize>) -> Result<Expr, AsmError> {
 
         // none
         if self.is_endline() || self.is_eof() || self.is_comment() {
-            return Ok(Expr::INSTR(instr, AdrMode::IMPL, Operand::NONE));
+            let mode = if has_accumulator_mode(&instr) { AdrMode::ACC } else { AdrMode::IMPL };
+            return Ok(Expr::INSTR(instr, mode, Operand::NONE, false));
         }
 
-        // branching BXX
-        if is_branching(&instr) {
-            match canonicalize_number(self.curr()) {
-                Ok(number) => {
-                    let op = Operand::VALUE(number);
-                    return Ok(Expr::INSTR(instr, AdrMode::REL, op));
-                },
-                Err(e) => {
-                    match self.curr() {
-                        Token::LITERAL(s) => {
-                            let op = Operand::LABEL(s.clone());
-                            return Ok(Expr::INSTR(instr, AdrMode::REL, op));
-                        },
-                        _ => { return Err(e) }
-                    }
+        // accumulator, `a` alone is the register rather than a label
+        if has_accumulator_mode(&instr) {
+            if let Token::LITERAL(reg) = self.curr() {
+                let ends = matches!(self.peek_at(1), Token::NEWLINE | Token::EOF | Token::COMMENT(..));
+                if reg.eq_ignore_ascii_case("a") && ends {
+                    self.next();
+                    return Ok(Expr::INSTR(instr, AdrMode::ACC, Operand::NONE, false));
                 }
             }
         }
 
+        // branching BXX, the operand is the target address
+        if is_branching(&instr) {
+            let op = match self.consume_operand()? {
+                Operand::EXPR(MathExpr::PLACEHOLDER(s)) => Operand::LABEL(s),
+                op => op
+            };
+            return Ok(Expr::INSTR(instr, AdrMode::REL, op, false));
+        }
+
         // immidiate
         if *self.curr() == Token::HASH {
             self.consume(Token::HASH)?;
-            let expr = &self.consume_math_expr()?;
-            let number = self.eval_math(expr)?;
-            let op = Operand::VALUE(number);
-            return Ok(Expr::INSTR(instr, AdrMode::IMM, op));
+            let op = self.consume_operand()?;
+            return Ok(Expr::INSTR(instr, AdrMode::IMM, op, false));
         }
 
         // ind, indx, indy
         if *self.curr() == Token::PARENTOPEN {
-            // indirect
             self.consume(Token::PARENTOPEN)?;
-            let number = self.try_expand_math()?;
-            if number.size > 8 {
-                let op = Operand::VALUE(number);
-                self.consume(Token::PARENTCLOSE)?;
-                return Ok(Expr::INSTR(instr, AdrMode::IND, op));
-            } else {
-                let op = Operand::VALUE(number);
-                if *self.curr() == Token::COMMA {
-                    // indirect x
-                    self.consume(Token::COMMA)?;
-                    self.consume_literal("x")?;
-                    self.consume(Token::PARENTCLOSE)?;
-                    return Ok(Expr::INSTR(instr, AdrMode::INDX, op));
-                } else {
-                    // indirect y
-                    self.consume(Token::PARENTCLOSE)?;
-                    self.consume(Token::COMMA)?;
-                    self.consume_literal("y")?;
-                    return Ok(Expr::INSTR(instr, AdrMode::INDY, op));
-                }
-            }
-        }
-
-        // abs and zp
-        let number = self.try_expand_math()?;
-        if number.size > 8 {
-            // abs
-            let op = Operand::VALUE(number);
-            let mut mode = AdrMode::ABS;
+            let op = self.consume_operand()?;
             if *self.curr() == Token::COMMA {
+                // indirect x
                 self.consume(Token::COMMA)?;
-                match self.consume_literal("x") {
-                    Ok(_) =>  { mode = AdrMode::ABSX },
-                    Err(_) => {
-                        self.consume_literal("y")?;
-                        mode = AdrMode::ABSY;
-                    }
-                };
+                self.consume_literal("x")?;
+                self.consume(Token::PARENTCLOSE)?;
+                return Ok(Expr::INSTR(instr, AdrMode::INDX, op, false));
             }
-            return Ok(Expr::INSTR(instr, mode, op));
-        } else {
-            // zp
-            let op = Operand::VALUE(number);
-            let mut mode = AdrMode::ZP;
+            self.consume(Token::PARENTCLOSE)?;
             if *self.curr() == Token::COMMA {
+                // indirect y
                 self.consume(Token::COMMA)?;
-                match self.consume_literal("x") {
-                    Ok(_) =>  { mode = AdrMode::ZPX },
-                    Err(_) => {
-                        self.consume_literal("y")?;
-                        mode = AdrMode::ZPY;
-                    }
-                };
+                self.consume_literal("y")?;
+                return Ok(Expr::INSTR(instr, AdrMode::INDY, op, false));
             }
-            return Ok(Expr::INSTR(instr, mode, op));
+            // indirect, always a 2 bytes address
+            let op = match op {
+                Operand::VALUE(number) => Operand::VALUE(NumericValue { size: 16, ..number }),
+                op => op
+            };
+            return Ok(Expr::INSTR(instr, AdrMode::IND, op, false));
         }
+
+        // abs and zp, possibly forced by a prefix
+        let mut width = suffix;
+        if let Token::LITERAL(prefix) = self.curr().clone() {
+            let forced = match prefix.to_lowercase().as_str() {
+                "a" => Some(16),
+                "z" => Some(8),
+                _ => None
+            };
+            if forced.is_some() && *self.peek_next() == Token::COLON {
+                if suffix.is_some() && suffix != forced {
+                    return Err(AsmError::parse(format!("{}: contradicts the width suffix of {}", prefix, instr)));
+                }
+                width = forced;
+                self.next();
+                self.consume(Token::COLON)?;
+            }
+        }
+        let op = self.consume_operand()?;
+        let mut index = None;
+        if *self.curr() == Token::COMMA {
+            self.consume(Token::COMMA)?;
+            match self.consume_literal("x") {
+                Ok(_) => { index = Some("x") },
+                Err(_) => {
+                    self.consume_literal("y")?;
+                    index = Some("y");
+                }
+            };
+        }
+        let mode = match index {
+            None => AdrMode::ABS,
+            Some("x") => AdrMode::ABSX,
+            _ => AdrMode::ABSY
+        };
+        // a small value stays absolute when the instruction has no zero page mode
+        let zero_page = zero_page_mode(&mode).filter(|zp| match (width, &op) {
+            (Some(width), _) => width == 8,
+            (None, Operand::VALUE(number)) => {
+                number.size <= 8 && OPCODES.contains_key(&(instr.clone(), zp.clone()))
+            },
+            _ => false
+        });
+        let mode = zero_page.unwrap_or(mode);
+        if let (AdrMode::ZP | AdrMode::ZPX | AdrMode::ZPY, Operand::VALUE(number)) = (&mode, &op) {
+            if !number.fits(8, false) {
+                return Err(AsmError::range(format!("operand {} is not a zero page address", number.value)));
+            }
+        }
+        Ok(Expr::INSTR(instr, mode, op, width.is_some()))
     }
-}
\ No newline at end of file
+}
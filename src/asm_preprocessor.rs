@@ -0,0 +1,323 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::asm_lexer::{AsmLexer, Token, generated_name};
+use crate::asm_parser::is_directive;
+use crate::error::AsmError;
+use crate::opcodes::INSTR;
+use crate::span::{Expansion, SourceFile, Span, Spanned};
+
+const MAX_MACRO_DEPTH: usize = 64;
+
+/// .macro NAME arg1, arg2, ... argN \
+/// body \
+/// .endmacro
+#[derive(Debug, Clone)]
+pub struct Macro {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Vec<Spanned<Token>>,
+    /// Labels declared in the body, renamed at each expansion
+    pub labels: HashSet<String>,
+    /// Location of the .macro directive
+    pub span: Span
+}
+
+/// Tokens replacing a statement, and the position of the first token after it
+pub type Replacement = (Vec<Spanned<Token>>, usize);
+
+/// Token to token transformation, run by the parser on each statement
+/// it assembles so that skipped conditional blocks are left untouched
+#[derive(Default)]
+pub struct AsmPreprocessor {
+    macros: HashMap<String, Macro>,
+    /// Number of macro expansions so far, makes local labels unique
+    expansions: usize,
+    /// Distance from the end of the tokens to the end of each
+    /// macro expansion being parsed, innermost last
+    expanding: Vec<usize>,
+    /// Searched in order when an included file is not found
+    /// next to the file including it
+    include_dirs: Vec<PathBuf>
+}
+
+impl AsmPreprocessor {
+    pub fn new(include_dirs: Vec<PathBuf>) -> Self {
+        Self {
+            macros: HashMap::new(),
+            expansions: 0,
+            expanding: vec![],
+            include_dirs
+        }
+    }
+
+    /// Replacement of the statement at `start` when it defines a macro,
+    /// includes a file or calls a macro.
+    /// The caller splices it in place of the statement and parses it next
+    pub fn expand_statement(
+        &mut self,
+        tokens: &[Spanned<Token>],
+        start: usize
+    ) -> Result<Option<Replacement>, AsmError> {
+        // expansions parsed entirely, splices never move the end of the tokens
+        while self.expanding.last().is_some_and(|tail| start >= tokens.len() - tail) {
+            self.expanding.pop();
+        }
+        let depth = self.expanding.len();
+        let token = &tokens[start];
+        match &token.value {
+            Token::DIRECTIVE(name) if is_directive(name, &["macro", "mac"]) => {
+                if depth > 0 {
+                    return Err(AsmError::directive("macro definitions cannot be nested".to_string())
+                        .or_at(&token.span));
+                }
+                let end = self.define(tokens, start)?;
+                Ok(Some((vec![], end)))
+            },
+            Token::DIRECTIVE(name) if is_directive(name, &["include"]) => {
+                let path = match tokens.get(start + 1).map(|tk| &tk.value) {
+                    Some(Token::STR(path)) => path.to_owned(),
+                    token => {
+                        return Err(AsmError::parse(format!("file path was expected, got {:?}", token))
+                            .or_at(&token_span(tokens, start + 1)));
+                    }
+                };
+                let included = self
+                    .include(&path, &token.span)
+                    .map_err(|e| e.or_at(&token.span))?;
+                Ok(Some((included, start + 2)))
+            },
+            Token::DIRECTIVE(name) if is_directive(name, &["endmacro", "endm"]) => {
+                Err(AsmError::directive(".endmacro without a matching .macro".to_string())
+                    .or_at(&token.span))
+            },
+            Token::LITERAL(name) if self.macros.contains_key(name) => {
+                // a label or an assignment can share the name of a macro
+                let next = tokens.get(start + 1).map(|tk| &tk.value);
+                if next == Some(&Token::COLON) || next == Some(&Token::EQUAL) {
+                    return Ok(None);
+                }
+                if depth >= MAX_MACRO_DEPTH {
+                    return Err(AsmError::directive(format!(
+                        "macro {:?} expands more than {} levels deep",
+                        name, MAX_MACRO_DEPTH
+                    )).or_at(&token.span));
+                }
+                let end = line_end(tokens, start + 1);
+                let args = split_args(&tokens[start + 1..end]);
+                let body = self
+                    .substitute(name, args, &token.span)
+                    .map_err(|e| e.or_at(&token.span))?;
+                self.expanding.push(tokens.len() - end);
+                Ok(Some((body, end)))
+            },
+            _ => Ok(None)
+        }
+    }
+
+    /// Register the macro defined at `start`, returns the position
+    /// of the first token after .endmacro
+    fn define(&mut self, tokens: &[Spanned<Token>], start: usize) -> Result<usize, AsmError> {
+        let span = tokens[start].span.clone();
+        let mut cursor = start + 1;
+        let name = match tokens.get(cursor).map(|tk| &tk.value) {
+            Some(Token::LITERAL(name)) => name.to_owned(),
+            token => {
+                return Err(AsmError::parse(format!("macro name was expected, got {:?}", token))
+                    .or_at(&span));
+            }
+        };
+        if INSTR.contains_key(&name.to_uppercase()) {
+            return Err(AsmError::symbol(format!("macro {:?} would shadow an instruction", name))
+                .or_at(&tokens[cursor].span));
+        }
+        if self.macros.contains_key(&name) {
+            return Err(AsmError::symbol(format!("macro {:?} is already defined", name))
+                .or_at(&tokens[cursor].span));
+        }
+        cursor += 1;
+
+        // params, commas are optional
+        let mut params: Vec<String> = vec![];
+        let end = line_end(tokens, cursor);
+        while cursor < end {
+            match &tokens[cursor].value {
+                Token::COMMA => {},
+                Token::LITERAL(param) => {
+                    if params.contains(param) {
+                        return Err(AsmError::symbol(format!("parameter {:?} is declared twice", param))
+                            .or_at(&tokens[cursor].span));
+                    }
+                    params.push(param.to_owned());
+                },
+                token => {
+                    return Err(AsmError::parse(format!("parameter name was expected, got {:?}", token))
+                        .or_at(&tokens[cursor].span));
+                }
+            }
+            cursor += 1;
+        }
+
+        // body
+        let body_start = cursor;
+        loop {
+            match tokens.get(cursor).map(|tk| &tk.value) {
+                None | Some(Token::EOF) => {
+                    return Err(AsmError::directive(format!("macro {:?} is never closed by .endmacro", name))
+                        .or_at(&span));
+                },
+                Some(Token::DIRECTIVE(dir)) if is_directive(dir, &["endmacro", "endm"]) => break,
+                Some(Token::DIRECTIVE(dir)) if is_directive(dir, &["macro", "mac"]) => {
+                    return Err(AsmError::directive("macro definitions cannot be nested".to_string())
+                        .or_at(&tokens[cursor].span));
+                },
+                _ => cursor += 1
+            }
+        }
+        let body = tokens[body_start..cursor].to_vec();
+        let labels = (1..body.len())
+            .filter_map(|pos| {
+                let token = |offset: usize| body.get(pos + offset).map(|tk| &tk.value);
+                // BNE :+ is a reference to an anonymous label
+                let is_reference = matches!(token(2), Some(Token::PLUS | Token::MINUS));
+                match (&body[pos - 1].value, &body[pos].value, token(1)) {
+                    (Token::NEWLINE, Token::LITERAL(label), Some(Token::COLON)) if !is_reference => {
+                        Some(label.to_owned())
+                    },
+                    // .name: is a local label
+                    (Token::NEWLINE, Token::DIRECTIVE(local), Some(Token::COLON)) => Some(format!(".{}", local)),
+                    _ => None
+                }
+            })
+            .collect();
+
+        self.macros.insert(name.clone(), Macro { name, params, body, labels, span });
+        Ok(cursor + 1)
+    }
+
+    /// Tokens of the file at `path`, relative to the file of `from`
+    /// or to one of the include directories
+    fn include(&self, path: &str, from: &Span) -> Result<Vec<Spanned<Token>>, AsmError> {
+        let resolved = resolve_path(path, from, &self.include_dirs)?;
+
+        // cycle detection, every file is compared to the chain of includers
+        let canonical = fs::canonicalize(&resolved)
+            .map_err(|e| AsmError::io(path, e.to_string()))?;
+        let chain = std::iter::once(from.clone()).chain(from.file.include_stack());
+        for span in chain {
+            if fs::canonicalize(&span.file.name).ok().as_ref() == Some(&canonical) {
+                return Err(AsmError::directive(format!(
+                    "include cycle, {:?} is already being included",
+                    resolved.display().to_string()
+                )));
+            }
+        }
+
+        let name = resolved.display().to_string();
+        let text = fs::read_to_string(&resolved)
+            .map_err(|e| AsmError::io(&name, e.to_string()))?;
+        let mut lexer = AsmLexer::from_file(SourceFile::included(&name, &text, from.clone()));
+        let mut tokens = lexer.tokenize_spanned()?;
+        // the file ends the current line, not the program
+        if let Some(last) = tokens.last_mut() {
+            last.value = Token::NEWLINE;
+        }
+        Ok(tokens)
+    }
+
+    /// Body of the macro `name` with each parameter replaced by its argument,
+    /// the tokens of the body remember the location of the `call`
+    fn substitute(&mut self, name: &str, args: Vec<Vec<Spanned<Token>>>, call: &Span) -> Result<Vec<Spanned<Token>>, AsmError> {
+        let mac = &self.macros[name];
+        if args.len() != mac.params.len() {
+            return Err(AsmError::parse(format!(
+                "macro {:?} expects {} argument(s), {} given",
+                name, mac.params.len(), args.len()
+            )));
+        }
+        self.expansions += 1;
+        let expansion = Arc::new(Expansion { name: name.to_string(), call: call.clone() });
+        let mut body = vec![];
+        for token in &mac.body {
+            let span = Span { expanded_from: Some(expansion.clone()), ..token.span.clone() };
+            match &token.value {
+                Token::LITERAL(lit) => {
+                    if let Some(pos) = mac.params.iter().position(|p| p == lit) {
+                        body.extend_from_slice(&args[pos]);
+                    } else if mac.labels.contains(lit) {
+                        // unique per expansion
+                        let value = Token::LITERAL(generated_name(lit, self.expansions));
+                        body.push(Spanned { value, span });
+                    } else {
+                        body.push(Spanned { value: token.value.clone(), span });
+                    }
+                },
+                Token::DIRECTIVE(local) if mac.labels.contains(&format!(".{}", local)) => {
+                    let value = Token::DIRECTIVE(generated_name(local, self.expansions));
+                    body.push(Spanned { value, span });
+                },
+                value => body.push(Spanned { value: value.clone(), span })
+            }
+        }
+        Ok(body)
+    }
+}
+
+/// Find `path` next to the file of `from`, then in each of `include_dirs`
+pub fn resolve_path(path: &str, from: &Span, include_dirs: &[PathBuf]) -> Result<PathBuf, AsmError> {
+    let base = Path::new(&from.file.name)
+        .parent()
+        .map(|dir| dir.to_path_buf())
+        .unwrap_or_default();
+    std::iter::once(base)
+        .chain(include_dirs.iter().cloned())
+        .map(|dir| dir.join(path))
+        .find(|candidate| candidate.is_file())
+        .ok_or_else(|| AsmError::io(path, "file not found".to_string()))
+}
+
+fn token_span(tokens: &[Spanned<Token>], pos: usize) -> Span {
+    tokens
+        .get(pos)
+        .or(tokens.last())
+        .map(|tk| tk.span.clone())
+        .unwrap_or_default()
+}
+
+/// Position of the first token ending the line started before `start`
+fn line_end(tokens: &[Spanned<Token>], start: usize) -> usize {
+    let mut end = start;
+    while let Some(token) = tokens.get(end) {
+        match token.value {
+            Token::NEWLINE | Token::COMMENT(..) | Token::EOF => break,
+            _ => end += 1
+        }
+    }
+    end
+}
+
+/// Split macro arguments on commas, commas within parenthesis are kept
+fn split_args(tokens: &[Spanned<Token>]) -> Vec<Vec<Spanned<Token>>> {
+    let mut args = vec![];
+    if tokens.is_empty() {
+        return args;
+    }
+    let mut current = vec![];
+    let mut nesting = 0;
+    for token in tokens {
+        match token.value {
+            Token::PARENTOPEN => nesting += 1,
+            Token::PARENTCLOSE => nesting -= 1,
+            Token::COMMA if nesting <= 0 => {
+                args.push(std::mem::take(&mut current));
+                continue;
+            },
+            _ => {}
+        }
+        current.push(token.clone());
+    }
+    args.push(current);
+    args
+}
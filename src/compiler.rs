@@ -1,96 +1,284 @@
 use std::{
-    path::Path, 
-    collections::HashMap, 
+    path::{Path, PathBuf}, 
+    collections::{HashMap, HashSet}, 
     io::Write, 
-    cell::RefCell
+    cell::RefCell,
+    sync::Arc
 };
 
-use lazy_static::__Deref;
-
 use crate::{
     asm_parser::{
         Expr, 
         Operand, 
         Directive, 
-        AsmParser
+        AsmParser,
+        MathExpr,
+        NumericValue,
+        eval_math_expr,
+        PC_SYMBOL
     }, 
     opcodes::{
         OPCODES, 
         AdrMode, 
         Instr, 
-        Opcode
+        Opcode,
+        Stability,
+        inverted_branch,
+        long_branch,
+        zero_page_mode
     }, 
-    asm_lexer::AsmLexer
+    asm_lexer::{AsmLexer, generated_name},
+    asm_preprocessor::{AsmPreprocessor, resolve_path},
+    error::{AsmError, AsmWarning},
+    span::{SourceFile, Span, Spanned}
 };
 
 use std::fs;
 
+/// Layout passes before giving up on operand sizes settling
+const MAX_PASSES: usize = 16;
+
 // Examples:
 // Absolute Y: AND $4400,Y consumes $44 and $00, Y is for notation
 // Indirect X: AND ($44,X) consumes $44 only, X is for notation
 // Zero Page, Immediate : AND $44 consumes $44 only
 pub fn canonical_op_len(adr_mode: &AdrMode) -> i8 {
     match adr_mode {
-        AdrMode::IMPL => 0,
+        AdrMode::IMPL | AdrMode::ACC => 0,
         AdrMode::IMM | AdrMode::ZP | AdrMode::ZPX | AdrMode::ZPY 
         | AdrMode::INDX | AdrMode::INDY | AdrMode::REL => 1,
         AdrMode::ABS | AdrMode::ABSX | AdrMode::ABSY | AdrMode::IND => 2,
     }
 }
 
+/// Local labels (@name, .name) only live between two global labels
+fn is_local(label: &str) -> bool {
+    label.starts_with('@') || label.starts_with('.')
+}
+
+/// Key of the local label `label` in `scope`
+fn local_key(scope: &str, label: &str) -> String {
+    generated_name(scope, label)
+}
+
+/// Encoding of `instr` in `mode`: one of `prefer` first, then the official one,
+/// unofficial opcodes can only be picked when `allow_illegal` is set
 pub fn get_opcode(
     instr: Instr, 
     mode: AdrMode, 
-    config: Option<CompilerConfig>
-) -> Result<Opcode, String> {
-    let opcodes = OPCODES.get(&(instr.clone(), mode.clone()));
-    if let Some(opcodes) = opcodes {
-        if let Some(ref config) = config {
-            for opcode in opcodes {
-                for given in config.allow_list.borrow().deref() {
-                    if config.allow_illegal && opcode.hex == *given {
-                        return Ok(opcode.to_owned());
-                    }
+    allow_illegal: bool,
+    prefer: &[u8]
+) -> Result<Opcode, AsmError> {
+    let Some(opcodes) = OPCODES.get(&(instr.clone(), mode.clone())) else {
+        return Err(AsmError::opcode(&instr, &mode));
+    };
+    let allowed = |opcode: &&Opcode| opcode.official || allow_illegal;
+    opcodes
+        .iter()
+        .filter(allowed)
+        .find(|opcode| prefer.contains(&opcode.hex))
+        .or_else(|| opcodes.iter().find(|opcode| opcode.official))
+        .or_else(|| opcodes.iter().find(allowed))
+        .cloned()
+        .ok_or_else(|| AsmError::illegal(&instr, &mode))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CompilerConfig {
+    /// Allow unofficial opcodes, until a .illegal directive says otherwise
+    pub allow_illegal: bool,
+    /// Compile for NES
+    pub enable_nes: bool,
+    /// Opcodes picked first when an instruction has several encodings
+    pub allow_list: RefCell<Vec<u8>>,
+    /// Address of the first byte of the program, until a .org directive
+    pub origin: usize,
+    /// Directories searched by .include
+    pub include_dirs: Vec<PathBuf>,
+    /// Rewrite branches out of reach into an inverted branch over a JMP
+    pub relax_branches: bool,
+    /// Unstable unofficial opcodes are errors rather than warnings
+    pub strict: bool,
+    /// Allow opcodes that jam the processor (STP)
+    pub allow_jam: bool
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FixupKind {
+    /// Signed offset relative to the next instruction (branching)
+    REL8,
+    /// 2 bytes little-endian address
+    ABS16,
+    /// 1 byte value (immediate), must fit in 8 bits
+    LO,
+    /// 1 byte zero page address
+    ZP,
+    /// High byte of a 2 bytes value
+    HI,
+    /// Low byte of a 2 bytes value
+    LOBYTE,
+    /// 2 bytes little-endian value
+    DATA16,
+    /// 2 bytes big-endian value
+    DATA16BE,
+    /// 3 bytes little-endian value
+    DATA24
+}
+
+impl FixupKind {
+    /// Number of bytes patched in the program
+    pub fn size(&self) -> usize {
+        match self {
+            FixupKind::REL8 | FixupKind::LO | FixupKind::ZP | FixupKind::HI | FixupKind::LOBYTE => 1,
+            FixupKind::ABS16 | FixupKind::DATA16 | FixupKind::DATA16BE => 2,
+            FixupKind::DATA24 => 3
+        }
+    }
+
+    /// Bytes of `number` as stored in the program, or why it does not fit,
+    /// the value of REL8 is the offset itself
+    pub fn encode(&self, number: &NumericValue) -> Result<Vec<u8>, &'static str> {
+        let value = number.value;
+        match self {
+            FixupKind::REL8 => {
+                if !(-128..=127).contains(&value) {
+                    return Err("is too far for a relative branch");
                 }
-            }
-            // allow_list does not match, just return whatever we get
-            // but prioritize official
-            let official: Vec<Opcode> = opcodes
-                .iter()
-                .filter(|op| op.official)
-                .map(|op| op.to_owned())
-                .collect();
-            if official.len() > 0 {
-                return Ok(official.get(0).unwrap().to_owned());
-            } else {
-                return Ok(opcodes.get(0).unwrap().to_owned());
+                Ok(vec![value as u8])
+            },
+            FixupKind::ABS16 => {
+                if !number.fits(16, false) {
+                    return Err("is out of the address space");
+                }
+                Ok(vec![value as u8, (value >> 8) as u8])
+            },
+            FixupKind::LO => {
+                if !number.fits(8, true) {
+                    return Err("does not fit in 1 byte");
+                }
+                Ok(vec![value as u8])
+            },
+            FixupKind::ZP => {
+                if !number.fits(8, false) {
+                    return Err("is not a zero page address");
+                }
+                Ok(vec![value as u8])
+            },
+            FixupKind::HI | FixupKind::LOBYTE => {
+                if !number.fits(16, true) {
+                    return Err("does not fit in 2 bytes");
+                }
+                let byte = if *self == FixupKind::HI { value >> 8 } else { value };
+                Ok(vec![byte as u8])
+            },
+            FixupKind::DATA16 | FixupKind::DATA16BE => {
+                if !number.fits(16, true) {
+                    return Err("does not fit in 2 bytes");
+                }
+                let bytes = vec![value as u8, (value >> 8) as u8];
+                if *self == FixupKind::DATA16BE {
+                    return Ok(bytes.into_iter().rev().collect());
+                }
+                Ok(bytes)
+            },
+            FixupKind::DATA24 => {
+                if !number.fits(24, true) {
+                    return Err("does not fit in 3 bytes");
+                }
+                Ok(vec![value as u8, (value >> 8) as u8, (value >> 16) as u8])
             }
         }
-        // try official if above failed or is None
-        for opcode in opcodes {
-            if opcode.official {
-                return Ok(opcode.to_owned());
-            }
+    }
+}
+
+/// Placeholder in the program that can only be patched
+/// once every label position is known
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixup {
+    /// Position of the placeholder in the program
+    pub location: usize,
+    /// Address of the placeholder once loaded in memory
+    pub address: usize,
+    pub kind: FixupKind,
+    pub expr: MathExpr,
+    pub context: SymbolContext,
+    /// Location of the line the fixup originates from
+    pub span: Span
+}
+
+/// Where an expression is evaluated, symbols are looked up from there
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolContext {
+    /// Address of the instruction or data using the expression (`*`)
+    pub pc: usize,
+    /// Last global label, local labels (@name, .name) belong to it
+    pub scope: String,
+    /// Enclosing .proc and .scope, outermost first
+    pub namespace: Vec<String>
+}
+
+/// .proc or .scope being compiled
+struct Namespace {
+    name: String,
+    is_proc: bool,
+    /// Location of the opening directive
+    span: Span,
+    /// Scope of the local labels before the namespace was opened
+    outer_scope: String
+}
+
+impl Namespace {
+    fn describe(&self) -> String {
+        if self.is_proc {
+            format!(".proc {}", self.name)
+        } else {
+            ".scope".to_string()
         }
     }
-    Err(format!("instruction ({}, {:?}) does not exist", instr, mode))
 }
 
-#[derive(Debug, Clone)]
-pub struct CompilerConfig {
-    /// Allow illegal opcode
-    pub allow_illegal: bool,
-    /// Compile for NES
-    pub enable_nes: bool,
-    /// Illegal opcodes will be picked using this list as hint
-    pub allow_list: RefCell<Vec<u8>>
+/// Operand whose encoding depends on the value it resolves to: symbolic
+/// absolute operands that could be zero page, branches that could be relaxed
+struct Sizable {
+    /// Position of the instruction in the lines
+    line: usize,
+    expr: MathExpr,
+    context: SymbolContext,
+    span: Span
+}
+
+/// Full name of `name` declared in `namespace`: proc::name
+fn qualify(namespace: &[String], name: &str) -> String {
+    if namespace.is_empty() {
+        return name.to_string();
+    }
+    format!("{}::{}", namespace.join("::"), name)
 }
 
 pub struct Compiler {
-    lines: Vec<Expr>,
+    lines: Vec<Spanned<Expr>>,
     prog_counter: usize,
     label_pos: HashMap<String, isize>,
-    jumpto_pos: HashMap<String, isize>,
+    /// Last global label, local labels (@name, .name) belong to it
+    scope: String,
+    namespaces: Vec<Namespace>,
+    /// Number of .scope opened so far, names the anonymous ones
+    scope_count: usize,
+    /// In a BSS or ZEROPAGE segment, nothing is stored in the program
+    uninitialised: bool,
+    /// Unofficial opcodes allowed at this point of the source
+    illegal: bool,
+    /// Lines whose symbolic operand fit in zero page in the previous pass
+    zero_page: HashSet<usize>,
+    sizables: Vec<Sizable>,
+    /// Lines whose branch is out of reach, emitted as a branch over a JMP
+    long_branches: HashSet<usize>,
+    branches: Vec<Sizable>,
+    fixups: Vec<Fixup>,
+    warnings: Vec<AsmWarning>,
+    variables: HashMap<String, MathExpr>,
+    /// Variables bound to the program counter so far in the pass
+    pc_variables: HashSet<String>,
     config: Option<CompilerConfig>
 }
 
@@ -102,191 +290,719 @@ impl Compiler {
             lines: vec![],
             prog_counter: 0,
             label_pos: HashMap::new(),
-            jumpto_pos: HashMap::new(),
+            scope: String::new(),
+            namespaces: vec![],
+            scope_count: 0,
+            uninitialised: false,
+            illegal: false,
+            zero_page: HashSet::new(),
+            sizables: vec![],
+            long_branches: HashSet::new(),
+            branches: vec![],
+            fixups: vec![],
+            warnings: vec![],
+            variables: HashMap::new(),
+            pc_variables: HashSet::new(),
             config
         }
     }
 
-    pub fn init<P: AsRef<Path>>(&mut self, source_path: P) -> Result<(), String>{
-        let contents = fs::read_to_string(source_path)
-            .expect("unable to read source file");
-        self.init_source(&contents)?;
-        Ok(())
+    pub fn init<P: AsRef<Path>>(&mut self, source_path: P) -> Result<(), AsmError>{
+        let name = source_path.as_ref().display().to_string();
+        let contents = fs::read_to_string(&source_path)
+            .map_err(|e| AsmError::io(&name, e.to_string()))?;
+        self.init_file(SourceFile::new(&name, &contents))
     }
 
-    pub fn init_source(&mut self, source: &String) -> Result<(), String> {
-        let mut lexer = AsmLexer::new(source);
-        let tokens = lexer.tokenize()?;
-        let mut parser = AsmParser::new(&tokens);
-        self.lines = parser.parse()?;
+    pub fn init_source(&mut self, source: &str) -> Result<(), AsmError> {
+        self.init_file(SourceFile::new("<source>", source))
+    }
+
+    fn init_file(&mut self, file: Arc<SourceFile>) -> Result<(), AsmError> {
+        let mut lexer = AsmLexer::from_file(file);
+        let tokens = lexer.tokenize_spanned()?;
+        let mut parser = AsmParser::from_spanned(tokens)
+            .with_preprocessor(AsmPreprocessor::new(self.include_dirs()));
+        self.lines = parser.parse_spanned()?;
+        self.variables = parser.variables().clone();
         self.prog_counter = 0;
+        self.label_pos.clear();
+        self.fixups.clear();
         Ok(())
     }
 
     /// Compile source code
-    pub fn run<P: AsRef<Path>>(&mut self, dest: P) -> Result<(), String> {
+    pub fn run<P: AsRef<Path>>(&mut self, dest: P) -> Result<(), AsmError> {
         let bytes = self.to_byte_code()?;
-        let mut file = fs::File::create(dest)
-            .map_err(|e| e.to_string())?;
+        let name = dest.as_ref().display().to_string();
+        let mut file = fs::File::create(&dest)
+            .map_err(|e| AsmError::io(&name, e.to_string()))?;
         file.write_all(&bytes)
-            .map_err(|e| e.to_string())?;
+            .map_err(|e| AsmError::io(&name, e.to_string()))?;
         Ok(())
     }
 
     /// Compile source code to hex string
-    pub fn to_hex_string(&mut self) -> Result<String, String>{
+    pub fn to_hex_string(&mut self) -> Result<String, AsmError>{
         let buffer = self.to_byte_code()?;
         let mut out = String::new();
         for byte in buffer {
-            let hex = format!("{:#04x} ", byte);
-            out.push_str(hex.strip_prefix("0x").unwrap());
+            out.push_str(&format!("{:02x} ", byte));
         }
         Ok(out.trim().to_owned())
     }
 
     /// Compile source code to contiguous bytes
-    pub fn to_byte_code(&mut self) -> Result<Vec<u8>, String> {
+    ///
+    /// Symbolic operands are absolute and branches are short in the first pass,
+    /// the program is laid out again with zero page operands wherever they fit
+    /// and long branches wherever short ones do not reach until no size changes
+    pub fn to_byte_code(&mut self) -> Result<Vec<u8>, AsmError> {
+        self.zero_page.clear();
+        self.long_branches.clear();
+        let mut unsettled = None;
+        for _ in 0..MAX_PASSES {
+            let mut program = self.emit_pass()?;
+            let zero_page: HashSet<usize> = self.sizables
+                .iter()
+                .filter(|sizable| {
+                    self.eval_math(&sizable.expr, &sizable.context)
+                        .map(|number| number.fits(8, false))
+                        .unwrap_or(false)
+                })
+                .map(|sizable| sizable.line)
+                .collect();
+            // a long branch stays long, branches only grow
+            let long_branches: HashSet<usize> = self.branches
+                .iter()
+                .filter(|branch| self.long_branches.contains(&branch.line) || !self.reaches(branch))
+                .map(|branch| branch.line)
+                .collect();
+            if zero_page == self.zero_page && long_branches == self.long_branches {
+                self.resolve_fixups(&mut program)?;
+                return Ok(program);
+            }
+            unsettled = self.sizables
+                .iter()
+                .find(|sizable| zero_page.contains(&sizable.line) != self.zero_page.contains(&sizable.line))
+                .map(|sizable| (format!("`{}` keeps switching between zero page and absolute", sizable.expr), sizable.span.clone()))
+                .or_else(|| self.branches
+                    .iter()
+                    .find(|branch| !self.long_branches.contains(&branch.line) && long_branches.contains(&branch.line))
+                    .map(|branch| (format!("branch to `{}` is still being relaxed", branch.expr), branch.span.clone())));
+            self.zero_page = zero_page;
+            self.long_branches = long_branches;
+        }
+        let (reason, span) = unsettled.unwrap_or_default();
+        Err(AsmError::symbol(format!(
+            "operand sizes did not settle after {} passes, {}",
+            MAX_PASSES, reason
+        )).or_at(&span))
+    }
+
+    /// Number of branches rewritten into a branch over a JMP by the last compilation
+    pub fn relaxed_branches(&self) -> usize {
+        self.long_branches.len()
+    }
+
+    /// Whether the short form of `branch` reaches its target
+    fn reaches(&self, branch: &Sizable) -> bool {
+        match self.eval_math(&branch.expr, &branch.context) {
+            // relative to the instruction that follows
+            Ok(target) => (-128..=127).contains(&(target.value - (branch.context.pc + 2) as i64)),
+            // reported once the fixups are resolved
+            Err(_) => true
+        }
+    }
+
+    /// Lay out every line with the current operand sizes
+    fn emit_pass(&mut self) -> Result<Vec<u8>, AsmError> {
         let mut program: Vec<u8> = vec![];
-        self.prog_counter = 0;
+        self.prog_counter = self.origin();
+        self.label_pos.clear();
+        self.scope.clear();
+        self.namespaces.clear();
+        self.scope_count = 0;
+        self.uninitialised = false;
+        self.illegal = self.allow_illegal();
+        self.sizables.clear();
+        self.branches.clear();
+        self.fixups.clear();
+        self.warnings.clear();
+        self.pc_variables.clear();
         let mut header_index = 0;
-        for line in &self.lines {
-            match line {
-                Expr::LABEL(label) => {
+        let lines = std::mem::take(&mut self.lines);
+        let result = lines.iter().enumerate().try_for_each(|(index, line)| {
+            let start = self.prog_counter;
+            self.emit_line(index, line, &mut program, &mut header_index)
+                .and_then(|_| self.check_address_space(start))
+                .map_err(|e| e.or_at(&line.span))
+        });
+        self.lines = lines;
+        result?;
+        if let Some(namespace) = self.namespaces.last() {
+            let closing = if namespace.is_proc { ".endproc" } else { ".endscope" };
+            return Err(AsmError::directive(format!(
+                "{} is never closed by {}",
+                namespace.describe(), closing
+            )).or_at(&namespace.span));
+        }
+        Ok(program)
+    }
+
+    fn emit_line(
+        &mut self, 
+        index: usize,
+        line: &Spanned<Expr>, 
+        program: &mut Vec<u8>, 
+        header_index: &mut usize
+    ) -> Result<(), AsmError> {
+        match &line.value {
+            Expr::LABEL(label) => {
+                if is_local(label) {
+                    let key = local_key(&self.scope, label);
+                    if self.label_pos.contains_key(&key) {
+                        return Err(AsmError::symbol(format!(
+                            "local label {:?} is already defined in scope {:?}",
+                            label, self.scope
+                        )));
+                    }
+                    self.label_pos.insert(key, self.prog_counter as isize);
+                    return Ok(());
+                }
+                // anonymous labels are unique, they do not open a scope
+                if label.starts_with(':') {
                     self.label_pos.insert(label.to_owned(), self.prog_counter as isize);
-                },
-                Expr::DIRECTIVE(directive) => {
-                    match directive {
-                        Directive::BYTE(seq) => {
-                            for item in seq {
-                                assert!(item.size == 8);
-                                program.push(item.value as u8);
-                                self.prog_counter += 1;
-                            }
-                        },
-                        Directive::DWORD(seq) => {
-                            for item in seq {
-                                assert!(item.size == 16);
-                                let hi = ((item.value & 0xff00) >> 8) as u8;
-                                let lo = (item.value & 0x00ff) as u8;
-                                // little-endian
-                                program.push(lo);
-                                program.push(hi);
-                                self.prog_counter += 2;
-                            }
-                        },
-                        Directive::SEGMENT(dir_name) => {
-                            if !self.use_nes() {
-                                return Err(format!("segment directive for nes assembly mode not enabled"))
-                            }
-                            match dir_name.as_str() {
-                                "HEADER" => {
-                                    if self.prog_counter > 0 {
-                                        return Err(format!("segment HEADER must be at the start of the program"))
-                                    }
-                                    header_index += 1;
-                                },
-                                "CODE" => {
-                                    if header_index < 1 {
-                                        return Err(format!("segment HEADER not provided before segment CODE"));
-                                    }
-                                    header_index += 1;
-                                },
-                                "VECTORS" => {
-                                    if header_index < 1 {
-                                        return Err(format!("segment HEADER not provided before segment VECTORS"));
-                                    }
-                                    header_index += 1;
-                                },
-                                "CHARS" => {
-                                    if header_index < 1 {
-                                        return Err(format!("segment HEADER not provided before segment VECTORS"));
-                                    }
-                                    header_index += 1
-                                },
-                                other => {
-                                    return Err(format!("segment {:?} not supported", other))
+                    return Ok(());
+                }
+                self.scope = self.define_label(label)?;
+            },
+            Expr::DIRECTIVE(directive) => {
+                match directive {
+                    Directive::BYTE(seq) => self.emit_data(program, seq, FixupKind::LO, &line.span)?,
+                    Directive::DWORD(seq) => self.emit_data(program, seq, FixupKind::DATA16, &line.span)?,
+                    Directive::LOBYTES(seq) => self.emit_data(program, seq, FixupKind::LOBYTE, &line.span)?,
+                    Directive::HIBYTES(seq) => self.emit_data(program, seq, FixupKind::HI, &line.span)?,
+                    Directive::DBYT(seq) => self.emit_data(program, seq, FixupKind::DATA16BE, &line.span)?,
+                    Directive::FARADDR(seq) => self.emit_data(program, seq, FixupKind::DATA24, &line.span)?,
+                    Directive::SEGMENT(dir_name) => {
+                        if !self.use_nes() {
+                            return Err(AsmError::directive("segment directive for nes assembly mode not enabled".to_string()))
+                        }
+                        match dir_name.as_str() {
+                            "HEADER" => {
+                                if !program.is_empty() {
+                                    return Err(AsmError::directive("segment HEADER must be at the start of the program".to_string()))
+                                }
+                                *header_index += 1;
+                            },
+                            "CODE" => {
+                                if *header_index < 1 {
+                                    return Err(AsmError::directive("segment HEADER not provided before segment CODE".to_string()));
                                 }
+                                *header_index += 1;
+                            },
+                            "VECTORS" => {
+                                if *header_index < 1 {
+                                    return Err(AsmError::directive("segment HEADER not provided before segment VECTORS".to_string()));
+                                }
+                                *header_index += 1;
+                            },
+                            "CHARS" => {
+                                if *header_index < 1 {
+                                    return Err(AsmError::directive("segment HEADER not provided before segment VECTORS".to_string()));
+                                }
+                                *header_index += 1
+                            },
+                            "BSS" | "ZEROPAGE" => {
+                                if *header_index < 1 {
+                                    return Err(AsmError::directive(format!("segment HEADER not provided before segment {}", dir_name)));
+                                }
+                            },
+                            other => {
+                                return Err(AsmError::directive(format!("segment {:?} not supported", other)))
                             }
-                        },
-                        Directive::RESERVE(bytes) => todo!("nes rom :: allocation {} not possible", bytes),
-                        Directive::ENDPROC => todo!("nes rom"),
-                        Directive::PROC(_) => todo!("nes rom"),
+                        }
+                        self.uninitialised = matches!(dir_name.as_str(), "BSS" | "ZEROPAGE");
+                    },
+                    Directive::ORG(origin) => {
+                        if *origin > 0xffff {
+                            return Err(AsmError::range(format!("origin {:#06x} is out of the address space", origin)));
+                        }
+                        self.prog_counter = *origin;
+                    },
+                    Directive::INCBIN(path, offset, length) => {
+                        let resolved = resolve_path(path, &line.span, &self.include_dirs())?;
+                        let name = resolved.display().to_string();
+                        let bytes = fs::read(&resolved)
+                            .map_err(|e| AsmError::io(&name, e.to_string()))?;
+                        if *offset > bytes.len() {
+                            return Err(AsmError::range(format!(
+                                "offset {} is past the end of {:?} ({} bytes)",
+                                offset, name, bytes.len()
+                            )));
+                        }
+                        let length = length.unwrap_or(bytes.len() - offset);
+                        if length > bytes.len() - offset {
+                            return Err(AsmError::range(format!(
+                                "length {} from offset {} is past the end of {:?} ({} bytes)",
+                                length, offset, name, bytes.len()
+                            )));
+                        }
+                        program.extend_from_slice(&bytes[*offset..offset + length]);
+                        self.prog_counter += length;
+                    },
+                    Directive::RESERVE(size, fill) => {
+                        let size = self.eval_math(size, &self.context())?.value;
+                        let size = usize::try_from(size)
+                            .map_err(|_| AsmError::range(format!("size {} cannot be negative", size)))?;
+                        self.skip(program, size, *fill)?;
+                    },
+                    Directive::FILL(count, fill) => {
+                        self.skip(program, *count, *fill)?;
+                    },
+                    Directive::ALIGN(alignment, fill) => {
+                        let size = (alignment - self.prog_counter % alignment) % alignment;
+                        self.skip(program, size, *fill)?;
+                    },
+                    Directive::PAD(address, fill) => {
+                        if *address < self.prog_counter {
+                            return Err(AsmError::range(format!(
+                                "cannot pad to {:#06x}, the program is already at {:#06x}",
+                                address, self.prog_counter
+                            )));
+                        }
+                        self.skip(program, address - self.prog_counter, *fill)?;
+                    },
+                    Directive::ILLEGAL(allowed) => {
+                        self.illegal = *allowed;
+                    },
+                    Directive::PROC(name) => {
+                        let key = self.define_label(name)?;
+                        let outer_scope = std::mem::replace(&mut self.scope, key);
+                        self.namespaces.push(Namespace {
+                            name: name.to_owned(),
+                            is_proc: true,
+                            span: line.span.clone(),
+                            outer_scope
+                        });
+                    },
+                    Directive::SCOPE(name) => {
+                        self.scope_count += 1;
+                        self.namespaces.push(Namespace {
+                            name: name.clone().unwrap_or(generated_name("scope", self.scope_count)),
+                            is_proc: false,
+                            span: line.span.clone(),
+                            outer_scope: self.scope.clone()
+                        });
+                    },
+                    Directive::ENDPROC | Directive::ENDSCOPE => {
+                        let is_proc = *directive == Directive::ENDPROC;
+                        let closing = if is_proc { ".endproc" } else { ".endscope" };
+                        let namespace = self.namespaces
+                            .pop()
+                            .ok_or_else(|| AsmError::directive(format!("{} without a matching opening", closing)))?;
+                        if namespace.is_proc != is_proc {
+                            return Err(AsmError::directive(format!(
+                                "{} does not close {} opened at {:?}",
+                                closing, namespace.describe(), namespace.span
+                            )));
+                        }
+                        self.scope = namespace.outer_scope;
+                    },
+                }
+            },
+            Expr::INSTR(name, mode, op, forced) => {
+                let long = long_branch(name);
+                if *mode == AdrMode::REL && (long.is_some() || self.relax_branches()) {
+                    let name = long.unwrap_or(name.to_owned());
+                    return self.emit_branch(index, name, op, &line.span, program);
+                }
+                let context = self.context();
+                let mut mode = mode;
+                let zero_page = zero_page_mode(mode)
+                    .filter(|zp| self.opcode(name.to_owned(), zp.to_owned()).is_ok());
+                if let (Operand::EXPR(expr), Some(zp), false) = (op, &zero_page, forced) {
+                    self.sizables.push(Sizable {
+                        line: index,
+                        expr: self.capture(expr),
+                        context: context.clone(),
+                        span: line.span.clone()
+                    });
+                    if self.zero_page.contains(&index) {
+                        mode = zp;
                     }
-                },
-                Expr::INSTR(name, mode, op) => {
-                    let opcode = get_opcode(name.to_owned(), mode.to_owned(), self.config.to_owned())?;
-                    program.push(opcode.hex);
-                    self.prog_counter += 1; // instruction
-
-                    let initial_size = program.len();
-                    match op {
-                        Operand::LABEL(name) => {
-                            self.jumpto_pos.insert(name.to_owned(), self.prog_counter as isize);
-                            // just a placeholder
-                            program.push(0xab);
-                        },
-                        Operand::VALUE(num) => {
-                            assert!(num.size == 8 || num.size == 16);
-                            if num.size == 8 {
-                                program.push(num.value as u8);
-                            } else {
-                                let hi = ((num.value & 0xff00) >> 8) as u8;
-                                let lo = (num.value & 0x00ff) as u8;
-                                // little-endian
-                                program.push(lo);
-                                program.push(hi);
+                }
+                let opcode = self.opcode(name.to_owned(), mode.to_owned())?;
+                self.check_stability(&opcode, name, mode, &line.span)?;
+                program.push(opcode.hex);
+                self.prog_counter += 1; // instruction
+
+                match op {
+                    Operand::LABEL(name) => {
+                        self.fixups.push(Fixup {
+                            location: program.len(),
+                            address: self.prog_counter,
+                            kind: FixupKind::REL8,
+                            expr: self.capture(&MathExpr::PLACEHOLDER(name.to_owned())),
+                            context: context.clone(),
+                            span: line.span.clone()
+                        });
+                        // just a placeholder
+                        program.push(0xab);
+                    },
+                    Operand::VALUE(num) if *mode == AdrMode::REL => {
+                        // target address, relative to the instruction that follows
+                        if !num.fits(16, false) {
+                            return Err(AsmError::range(format!("branch target {} is out of the address space", num.value)));
+                        }
+                        let offset = num.value - (self.prog_counter + 1) as i64;
+                        if !(-128..=127).contains(&offset) {
+                            return Err(AsmError::range(format!(
+                                "relative offset too large {} ({:#06x})",
+                                offset, num.value
+                            )));
+                        }
+                        program.push(offset as i8 as u8);
+                    },
+                    Operand::VALUE(num) => {
+                        if canonical_op_len(mode) == 1 {
+                            // only immediates can be negative, others are addresses
+                            if !num.fits(8, *mode == AdrMode::IMM) {
+                                return Err(AsmError::range(format!(
+                                    "operand {} does not fit in 1 byte ({:?})",
+                                    num.value, mode
+                                )));
                             }
-                        },
-                        Operand::NONE => {},
-                    }
-                    let diff = program.len() - initial_size;
-                    assert_eq!(diff as i8, canonical_op_len(&mode), "invalid operand size");
-                    self.prog_counter += canonical_op_len(&mode) as usize; // operand
+                            program.push(num.value as u8);
+                        } else {
+                            if !num.fits(16, false) {
+                                return Err(AsmError::range(format!(
+                                    "operand {} is out of the address space ({:?})",
+                                    num.value, mode
+                                )));
+                            }
+                            let hi = ((num.value & 0xff00) >> 8) as u8;
+                            let lo = (num.value & 0x00ff) as u8;
+                            // little-endian
+                            program.push(lo);
+                            program.push(hi);
+                        }
+                    },
+                    Operand::EXPR(expr) => {
+                        let len = canonical_op_len(mode) as usize;
+                        let kind = match len {
+                            _ if *mode == AdrMode::REL => FixupKind::REL8,
+                            1 if *mode == AdrMode::IMM => FixupKind::LO,
+                            1 => FixupKind::ZP,
+                            _ => FixupKind::ABS16
+                        };
+                        self.fixups.push(Fixup {
+                            location: program.len(),
+                            address: self.prog_counter,
+                            kind,
+                            expr: self.capture(expr),
+                            context: context.clone(),
+                            span: line.span.clone()
+                        });
+                        // just a placeholder
+                        program.extend(vec![0xab; len]);
+                    },
+                    Operand::NONE => {},
+                }
+                self.prog_counter += canonical_op_len(mode) as usize; // operand
+            },
+            Expr::ASSIGN(name, expr) => {
+                // evaluated at parse time, unless bound to the program counter here
+                if expr.has_pc() {
+                    self.variables.insert(name.to_owned(), expr.with_pc(self.prog_counter));
+                    self.pc_variables.insert(name.to_owned());
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Emit a branch that can be relaxed, once out of reach `BEQ target`
+    /// becomes `BNE *+5` followed by `JMP target`
+    fn emit_branch(&mut self, index: usize, name: Instr, op: &Operand, span: &Span, program: &mut Vec<u8>) -> Result<(), AsmError> {
+        let target = match op {
+            Operand::LABEL(label) => MathExpr::PLACEHOLDER(label.to_owned()),
+            Operand::VALUE(num) => MathExpr::NUM(num.to_owned()),
+            Operand::EXPR(expr) => expr.to_owned(),
+            Operand::NONE => return Err(AsmError::parse(format!("{} expects a target", name)))
+        };
+        let context = self.context();
+        self.branches.push(Sizable {
+            line: index,
+            expr: self.capture(&target),
+            context: context.clone(),
+            span: span.clone()
+        });
+
+        let kind = if self.long_branches.contains(&index) {
+            let inverted = inverted_branch(&name)
+                .ok_or_else(|| AsmError::opcode(&name, &AdrMode::REL))?;
+            let skip = self.opcode(inverted, AdrMode::REL)?;
+            let jump = self.opcode(Instr::JMP, AdrMode::ABS)?;
+            // over the 3 bytes of the JMP
+            program.extend([skip.hex, 0x03, jump.hex]);
+            self.prog_counter += 3;
+            FixupKind::ABS16
+        } else {
+            let opcode = self.opcode(name, AdrMode::REL)?;
+            program.push(opcode.hex);
+            self.prog_counter += 1;
+            FixupKind::REL8
+        };
+        self.fixups.push(Fixup {
+            location: program.len(),
+            address: self.prog_counter,
+            kind: kind.clone(),
+            expr: self.capture(&target),
+            context,
+            span: span.clone()
+        });
+        // just a placeholder
+        program.extend(vec![0xab; kind.size()]);
+        self.prog_counter += kind.size();
+        Ok(())
+    }
+
+    /// Emit each item on `kind.size()` bytes, items depending on
+    /// labels are patched once every label is known
+    fn emit_data(&mut self, program: &mut Vec<u8>, seq: &[Operand], kind: FixupKind, span: &Span) -> Result<(), AsmError> {
+        for (pos, item) in seq.iter().enumerate() {
+            match item {
+                Operand::VALUE(num) => {
+                    let bytes = kind
+                        .encode(num)
+                        .map_err(|what| AsmError::range(format!("{}-th item {} {}", pos, num.value, what)))?;
+                    program.extend(bytes);
                 },
-                Expr::ASSIGN(..) => {}, // evaluated at parse time
+                Operand::EXPR(expr) => {
+                    self.fixups.push(Fixup {
+                        location: program.len(),
+                        address: self.prog_counter,
+                        kind: kind.clone(),
+                        expr: self.capture(expr),
+                        context: self.context(),
+                        span: span.clone()
+                    });
+                    // just a placeholder
+                    program.extend(vec![0xab; kind.size()]);
+                },
+                Operand::NONE | Operand::LABEL(_) => {
+                    return Err(AsmError::parse(format!("{}-th item is not a value", pos)));
+                }
             }
+            self.prog_counter += kind.size();
         }
+        Ok(())
+    }
 
-        // now resolve the jumps
-        for (label, pos) in &self.jumpto_pos {
-            match self.label_pos.get(label) {
-                Some(lab_pos) => {
-                    // [pos] .......... [pc]
-                    // delta = pc - pos
-                    let sign = if pos >= lab_pos {-1} else {1};
-                    let mut offset = (pos - lab_pos).abs();
-                    if sign * offset < -127 {
-                        return Err(format!("relative offset too large {}({}) < -128", offset, label));
-                    }
-                    if sign * offset > 127 {
-                        return Err(format!("relative offset too large {}({}) > 127", offset, label));
-                    }
-                    if sign < 0 {
-                        // BNE my_label <= pc is at my_label, so move -1
-                        offset += 1;
-                    } else {
-                        // BNE my_label <= pc will move ahead so no need to change
-                    }
-                    let sg_offset = offset * sign;
-                    println!("{} :: {}", label, sg_offset);
-                    program[*pos as usize] = sg_offset as u8;
-                },
-                None => {
-                    return Err(format!("unable to jump to invalid label {:?}", label))
+    /// Instructions and data emitted from `start` must end within $ffff
+    fn check_address_space(&self, start: usize) -> Result<(), AsmError> {
+        if self.prog_counter > 0x10000 {
+            return Err(AsmError::range(format!(
+                "{} bytes at {:#06x} go past the address space",
+                self.prog_counter - start, start
+            )));
+        }
+        Ok(())
+    }
+
+    /// Move the program counter `size` bytes ahead, filling the gap
+    /// unless the segment is uninitialised
+    fn skip(&mut self, program: &mut Vec<u8>, size: usize, fill: u8) -> Result<(), AsmError> {
+        if self.prog_counter + size > 0x10000 {
+            return Err(AsmError::range(format!(
+                "{} bytes at {:#06x} go past the address space",
+                size, self.prog_counter
+            )));
+        }
+        if !self.uninitialised {
+            program.resize(program.len() + size, fill);
+        }
+        self.prog_counter += size;
+        Ok(())
+    }
+
+    /// Encoding of `instr` in `mode` allowed at this point of the source
+    fn opcode(&self, instr: Instr, mode: AdrMode) -> Result<Opcode, AsmError> {
+        let prefer = match &self.config {
+            Some(config) => config.allow_list.borrow().clone(),
+            None => vec![]
+        };
+        get_opcode(instr, mode, self.illegal, &prefer)
+    }
+
+    /// Refuse jamming opcodes unless allowed, warn about unstable
+    /// ones or refuse them in strict mode
+    fn check_stability(&mut self, opcode: &Opcode, instr: &Instr, mode: &AdrMode, span: &Span) -> Result<(), AsmError> {
+        let (strict, allow_jam) = match &self.config {
+            Some(config) => (config.strict, config.allow_jam),
+            None => (false, false)
+        };
+        match opcode.stability {
+            Stability::STABLE => Ok(()),
+            Stability::JAM if allow_jam => Ok(()),
+            Stability::UNSTABLE | Stability::HIGHLYUNSTABLE if !strict => {
+                let warning = AsmError::unstable(instr, mode, opcode.stability);
+                self.warnings.push(AsmWarning { message: warning.message(), span: span.clone() });
+                Ok(())
+            },
+            stability => Err(AsmError::unstable(instr, mode, stability))
+        }
+    }
+
+    /// Warnings of the last compilation
+    pub fn warnings(&self) -> &[AsmWarning] {
+        &self.warnings
+    }
+
+    /// Current position in the program
+    fn context(&self) -> SymbolContext {
+        SymbolContext {
+            pc: self.prog_counter,
+            scope: self.scope.clone(),
+            namespace: self.namespaces
+                .iter()
+                .map(|namespace| namespace.name.to_owned())
+                .collect()
+        }
+    }
+
+    /// Declare a global label in the current namespace, returns its full name
+    fn define_label(&mut self, label: &str) -> Result<String, AsmError> {
+        if label.contains("::") {
+            return Err(AsmError::symbol(format!("label {:?} cannot be declared outside of its scope", label)));
+        }
+        let key = qualify(&self.context().namespace, label);
+        if self.label_pos.contains_key(&key) {
+            return Err(AsmError::symbol(format!("label {:?} is already defined", key)));
+        }
+        self.label_pos.insert(key.clone(), self.prog_counter as isize);
+        Ok(key)
+    }
+
+    /// Evaluate a math expression against the labels and the variables,
+    /// labels are looked up from the innermost namespace of `context`
+    fn eval_math(&self, expr: &MathExpr, context: &SymbolContext) -> Result<NumericValue, AsmError> {
+        eval_math_expr(expr, &|name: &str| {
+            if name == PC_SYMBOL {
+                return Ok(NumericValue { value: context.pc as i64, size: 16 });
+            }
+            if is_local(name) {
+                return match self.label_pos.get(&local_key(&context.scope, name)) {
+                    Some(pos) => Ok(NumericValue { value: *pos as i64, size: 16 }),
+                    None => Err(AsmError::undefined_in(name, &context.scope))
+                };
+            }
+            for depth in (0..=context.namespace.len()).rev() {
+                let key = qualify(&context.namespace[..depth], name);
+                if let Some(pos) = self.label_pos.get(&key) {
+                    return Ok(NumericValue { value: *pos as i64, size: 16 });
                 }
             }
+            match self.variables.get(name) {
+                Some(nested) => self.eval_math(nested, context),
+                None => Err(AsmError::undefined(name))
+            }
+        })
+    }
+
+    /// `expr` with the variables bound to the program counter so far replaced
+    /// by their value, a later `name = *` must not change what a fixup refers to
+    fn capture(&self, expr: &MathExpr) -> MathExpr {
+        match expr {
+            MathExpr::PLACEHOLDER(name) => {
+                let Some(nested) = self.variables.get(name) else {
+                    return expr.clone();
+                };
+                let captured = self.capture(nested);
+                if self.pc_variables.contains(name) || captured != *nested {
+                    return captured;
+                }
+                expr.clone()
+            },
+            MathExpr::BIN(op, lvalue, rvalue) => {
+                MathExpr::BIN(op.clone(), Box::new(self.capture(lvalue)), Box::new(self.capture(rvalue)))
+            },
+            MathExpr::UNARY(op, value) => MathExpr::UNARY(op.clone(), Box::new(self.capture(value))),
+            MathExpr::NUM(_) | MathExpr::PC => expr.clone()
         }
-        Ok(program)
+    }
+
+    /// Patch the placeholders now that every label is known
+    fn resolve_fixups(&self, program: &mut [u8]) -> Result<(), AsmError> {
+        for fixup in &self.fixups {
+            let number = self
+                .eval_math(&fixup.expr, &fixup.context)
+                .map_err(|e| e.or_at(&fixup.span))?;
+            let value = number.value;
+            let out_of_range = |what: &str| AsmError::range(format!(
+                "operand `{}` evaluates to {} which {}",
+                fixup.expr, value, what
+            )).or_at(&fixup.span);
+            if fixup.kind == FixupKind::REL8 {
+                if !number.fits(16, false) {
+                    return Err(out_of_range("is out of the address space"));
+                }
+                // relative to the instruction that follows
+                let next = (fixup.address + 1) as i64;
+                let offset = value - next;
+                if !(-128..=127).contains(&offset) {
+                    return Err(AsmError::range(format!(
+                        "relative offset too large {} ({})",
+                        offset, fixup.expr
+                    )).or_at(&fixup.span));
+                }
+                program[fixup.location] = offset as i8 as u8;
+                continue;
+            }
+            let bytes = fixup.kind.encode(&number).map_err(out_of_range)?;
+            program[fixup.location..fixup.location + bytes.len()].copy_from_slice(&bytes);
+        }
+        Ok(())
     }
 
     pub fn get_parse_string(&self) -> String {
         self.lines
             .iter()
-            .map(|v| format!("{:?}", v))
+            .map(|v| format!("{:?}", v.value))
             .collect::<Vec<String>>()
             .join("\n")
     }
 
+    pub fn origin(&self) -> usize {
+        match &self.config {
+            Some(config) => config.origin,
+            None => 0
+        }
+    }
+
+    pub fn allow_illegal(&self) -> bool {
+        match &self.config {
+            Some(config) => config.allow_illegal,
+            None => false
+        }
+    }
+
+    pub fn relax_branches(&self) -> bool {
+        match &self.config {
+            Some(config) => config.relax_branches,
+            None => false
+        }
+    }
+
+    pub fn include_dirs(&self) -> Vec<PathBuf> {
+        match &self.config {
+            Some(config) => config.include_dirs.clone(),
+            None => vec![]
+        }
+    }
+
     pub fn use_nes(&self) -> bool {
         if let Some(config) = &self.config {
             if config.enable_nes {
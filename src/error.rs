@@ -0,0 +1,166 @@
+use std::fmt;
+
+use crate::{
+    asm_lexer::source_name,
+    opcodes::{AdrMode, Instr, Stability},
+    span::Span
+};
+
+/// Every error the assembler can produce, each one is located in the source
+/// (the span is empty when the location is not known)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmError {
+    /// Unsupported character or malformed token
+    LEX { message: String, span: Span },
+    /// Unexpected token or malformed statement
+    PARSE { message: String, span: Span },
+    /// Symbol used but never defined, named as in the source, local labels name their scope
+    UNDEFINED { name: String, scope: Option<String>, span: Span },
+    /// Symbol defined twice or recursively
+    SYMBOL { message: String, span: Span },
+    /// Division by zero, overflow, ...
+    MATH { message: String, span: Span },
+    /// Value that does not fit where it is used
+    RANGE { message: String, span: Span },
+    /// Instruction that does not support the addressing mode
+    OPCODE { instr: Instr, mode: AdrMode, span: Span },
+    /// Unofficial opcode used where it is not allowed
+    ILLEGAL { instr: Instr, mode: AdrMode, span: Span },
+    /// Unstable opcode in strict mode, jamming opcode unless allowed
+    UNSTABLE { instr: Instr, mode: AdrMode, stability: Stability, span: Span },
+    /// Directive misused or in the wrong context
+    DIRECTIVE { message: String, span: Span },
+    /// Unable to read or write a file
+    IO { path: String, message: String, span: Span }
+}
+
+impl AsmError {
+    pub fn lex(message: String) -> Self {
+        Self::LEX { message, span: Span::default() }
+    }
+
+    pub fn parse(message: String) -> Self {
+        Self::PARSE { message, span: Span::default() }
+    }
+
+    pub fn undefined(name: &str) -> Self {
+        Self::UNDEFINED { name: source_name(name), scope: None, span: Span::default() }
+    }
+
+    pub fn undefined_in(name: &str, scope: &str) -> Self {
+        Self::UNDEFINED { name: source_name(name), scope: Some(source_name(scope)), span: Span::default() }
+    }
+
+    pub fn symbol(message: String) -> Self {
+        Self::SYMBOL { message, span: Span::default() }
+    }
+
+    pub fn math(message: String) -> Self {
+        Self::MATH { message, span: Span::default() }
+    }
+
+    pub fn range(message: String) -> Self {
+        Self::RANGE { message, span: Span::default() }
+    }
+
+    pub fn opcode(instr: &Instr, mode: &AdrMode) -> Self {
+        Self::OPCODE { instr: instr.clone(), mode: mode.clone(), span: Span::default() }
+    }
+
+    pub fn illegal(instr: &Instr, mode: &AdrMode) -> Self {
+        Self::ILLEGAL { instr: instr.clone(), mode: mode.clone(), span: Span::default() }
+    }
+
+    pub fn unstable(instr: &Instr, mode: &AdrMode, stability: Stability) -> Self {
+        Self::UNSTABLE { instr: instr.clone(), mode: mode.clone(), stability, span: Span::default() }
+    }
+
+    pub fn directive(message: String) -> Self {
+        Self::DIRECTIVE { message, span: Span::default() }
+    }
+
+    pub fn io(path: &str, message: String) -> Self {
+        Self::IO { path: path.to_string(), message, span: Span::default() }
+    }
+
+    pub fn span(&self) -> &Span {
+        match self {
+            Self::LEX { span, .. } | Self::PARSE { span, .. }
+            | Self::UNDEFINED { span, .. } | Self::SYMBOL { span, .. }
+            | Self::MATH { span, .. } | Self::RANGE { span, .. }
+            | Self::OPCODE { span, .. } | Self::ILLEGAL { span, .. }
+            | Self::UNSTABLE { span, .. } | Self::DIRECTIVE { span, .. }
+            | Self::IO { span, .. } => span
+        }
+    }
+
+    fn span_mut(&mut self) -> &mut Span {
+        match self {
+            Self::LEX { span, .. } | Self::PARSE { span, .. }
+            | Self::UNDEFINED { span, .. } | Self::SYMBOL { span, .. }
+            | Self::MATH { span, .. } | Self::RANGE { span, .. }
+            | Self::OPCODE { span, .. } | Self::ILLEGAL { span, .. }
+            | Self::UNSTABLE { span, .. } | Self::DIRECTIVE { span, .. }
+            | Self::IO { span, .. } => span
+        }
+    }
+
+    /// Locate the error at `span` unless it is already located
+    pub fn or_at(mut self, span: &Span) -> Self {
+        if self.span().line == 0 {
+            *self.span_mut() = span.clone();
+        }
+        self
+    }
+
+    /// Description of the error without its location
+    pub fn message(&self) -> String {
+        match self {
+            Self::LEX { message, .. } | Self::PARSE { message, .. }
+            | Self::SYMBOL { message, .. } | Self::MATH { message, .. }
+            | Self::RANGE { message, .. } | Self::DIRECTIVE { message, .. } => message.to_owned(),
+            Self::UNDEFINED { name, scope: None, .. } => format!("variable {:?} is undefined", name),
+            Self::UNDEFINED { name, scope: Some(scope), .. } if scope.is_empty() => {
+                format!("local label {:?} is undefined, no global label precedes it", name)
+            },
+            Self::UNDEFINED { name, scope: Some(scope), .. } => {
+                format!("local label {:?} is undefined in scope {:?}", name, scope)
+            },
+            Self::OPCODE { instr, mode, .. } => format!("instruction ({}, {:?}) does not exist", instr, mode),
+            Self::ILLEGAL { instr, mode, .. } => format!(
+                "instruction ({}, {:?}) is unofficial, allow it with --allow-illegal or .illegal on",
+                instr, mode
+            ),
+            Self::UNSTABLE { instr, mode, stability: Stability::JAM, .. } => format!(
+                "instruction ({}, {:?}) jams the processor, allow it with --allow-jam",
+                instr, mode
+            ),
+            Self::UNSTABLE { instr, mode, stability, .. } => format!(
+                "instruction ({}, {:?}) is {} on real hardware",
+                instr, mode, stability
+            ),
+            Self::IO { path, message, .. } => format!("{}: {}", path, message)
+        }
+    }
+}
+
+/// Problem that does not stop the compilation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmWarning {
+    pub message: String,
+    pub span: Span
+}
+
+impl fmt::Display for AsmWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.span.render_as("warning", &self.message))
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.span().render(&self.message()))
+    }
+}
+
+impl std::error::Error for AsmError {}
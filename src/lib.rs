@@ -1,7 +1,10 @@
 pub mod asm_lexer;
+pub mod asm_preprocessor;
 pub mod asm_parser;
 pub mod opcodes;
 pub mod compiler;
+pub mod span;
+pub mod error;
 
 #[cfg(test)]
 mod tests;
\ No newline at end of file
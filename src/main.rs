@@ -5,6 +5,7 @@ use r6502::compiler::Compiler;
 use clap::Parser;
 use clap::Subcommand;
 use r6502::compiler::CompilerConfig;
+use r6502::error::AsmError;
 
 #[derive(Subcommand, Debug)]
 enum Mode {
@@ -24,12 +25,61 @@ struct Args {
     /// Output mode
     #[clap(subcommand)]
     mode: Option<Mode>,
-    // todo
-    // add allow illegal + allow_list=hex list (should support any format)
+    /// Address of the program when no .org is given ($8000, 0x8000 or 32768)
+    #[arg(long, default_value = "0", value_parser = parse_number)]
+    origin: usize,
+    /// Directory searched by .include, can be repeated
+    #[arg(short = 'I', long = "include")]
+    include_dirs: Vec<PathBuf>,
+    /// Rewrite branches out of reach into an inverted branch over a JMP
+    #[arg(long)]
+    relax_branches: bool,
+    /// Allow unofficial opcodes, .illegal on/off overrides it in the source
+    #[arg(long)]
+    allow_illegal: bool,
+    /// Opcodes picked first when an instruction has several encodings (0xDA,$1A)
+    #[arg(long = "prefer-opcode", value_delimiter = ',', value_parser = parse_opcode)]
+    prefer_opcodes: Vec<u8>,
+    /// Unstable unofficial opcodes are errors rather than warnings
+    #[arg(long)]
+    strict: bool,
+    /// Allow opcodes that jam the processor (STP)
+    #[arg(long)]
+    allow_jam: bool,
 }
 
-fn main() -> Result<(), String> {
+/// Parse a number given as $hex, 0xhex, %bin or decimal
+fn parse_number(s: &str) -> Result<usize, String> {
+    let s = s.trim();
+    let (digits, radix) = if let Some(hex) = s.strip_prefix('$') {
+        (hex, 16)
+    } else if let Some(hex) = s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        (hex, 16)
+    } else if let Some(bin) = s.strip_prefix('%') {
+        (bin, 2)
+    } else {
+        (s, 10)
+    };
+    usize::from_str_radix(digits, radix)
+        .map_err(|e| format!("{:?} is not a valid number: {}", s, e))
+}
+
+/// Parse an opcode given in any format of `parse_number`
+fn parse_opcode(s: &str) -> Result<u8, String> {
+    let number = parse_number(s)?;
+    u8::try_from(number).map_err(|_| format!("{:?} is not an opcode, it does not fit in 1 byte", s))
+}
+
+fn main() {
     let args = Args::parse();
+    if let Err(e) = run(args) {
+        // already rendered with its location
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: Args) -> Result<(), AsmError> {
     let input = PathBuf::from(args.file);
     let output = match args.output {
         Some(path) => PathBuf::from(path),
@@ -38,8 +88,13 @@ fn main() -> Result<(), String> {
     
     let config = CompilerConfig {
         enable_nes: true,
-        allow_illegal: false,
-        allow_list: RefCell::new(vec![])
+        allow_illegal: args.allow_illegal,
+        allow_list: RefCell::new(args.prefer_opcodes),
+        origin: args.origin,
+        include_dirs: args.include_dirs,
+        relax_branches: args.relax_branches,
+        strict: args.strict,
+        allow_jam: args.allow_jam
     };
     let mut compiler = Compiler::new(Some(config));
     compiler.init(input)?;
@@ -56,6 +111,12 @@ fn main() -> Result<(), String> {
         compiler.run(&output)?;
         println!("Binary generated at {}", output.display());
     }
+    for warning in compiler.warnings() {
+        eprintln!("{}", warning);
+    }
+    if compiler.relaxed_branches() > 0 {
+        eprintln!("{} branch(es) relaxed into a branch over a JMP", compiler.relaxed_branches());
+    }
 
     Ok(())
 }
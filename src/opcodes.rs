@@ -3,7 +3,7 @@ use std::{collections::HashMap, fmt::Display};
 
 #[derive(Hash, Debug, Clone, PartialEq, Eq)]
 pub enum AdrMode {
-    IMPL, IMM, ABS,
+    IMPL, ACC, IMM, ABS,
     ABSX, ABSY,
     ZP, ZPX, ZPY,
     IND, INDX, INDY,
@@ -33,7 +33,10 @@ pub enum Instr {
     // unofficial instructions
     STP, SLO, ANC, RLA, SRE, ALR, RRA, ARR, SAX,
     XAA, AHX, TAS, SHY, SHX, LAX, LAS, DCP, AXS,
-    ISC
+    ISC,
+
+    // pseudo instructions, branches that reach anywhere
+    JCC, JCS, JEQ, JMI, JNE, JPL, JVC, JVS
 }
 
 impl Display for Instr {
@@ -42,10 +45,47 @@ impl Display for Instr {
     }
 }
 
+/// How reliably an opcode behaves on real hardware
+#[derive(Hash, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stability {
+    STABLE,
+    /// Result depends on the high byte of the address (AHX, SHX, SHY, TAS, LAS)
+    UNSTABLE,
+    /// Result depends on the chip and temperature (XAA, LAX #imm)
+    HIGHLYUNSTABLE,
+    /// Halts the processor until reset (STP)
+    JAM
+}
+
+impl Stability {
+    fn of(hex: u8) -> Self {
+        match hex {
+            0x8B | 0xAB => Stability::HIGHLYUNSTABLE,
+            0x93 | 0x9B | 0x9C | 0x9E | 0x9F | 0xBB => Stability::UNSTABLE,
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52
+            | 0x62 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => Stability::JAM,
+            _ => Stability::STABLE
+        }
+    }
+}
+
+impl Display for Stability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Stability::STABLE => "stable",
+            Stability::UNSTABLE => "unstable",
+            Stability::HIGHLYUNSTABLE => "highly unstable",
+            Stability::JAM => "jamming"
+        };
+        write!(f, "{}", name)
+    }
+}
+
 #[derive(Hash, Debug, Clone, PartialEq, Eq)]
 pub struct Opcode {
     pub hex: u8,
     pub official: bool,
+    pub stability: Stability,
     pub examples: Vec<String>
 }
 
@@ -54,6 +94,7 @@ impl Opcode {
         Self {
             hex,
             official,
+            stability: Stability::of(hex),
             examples
         }
     }
@@ -137,7 +178,15 @@ lazy_static! {
         ("LAS".to_string(), Instr::LAS),
         ("DCP".to_string(), Instr::DCP),
         ("AXS".to_string(), Instr::AXS),
-        ("ISC".to_string(), Instr::ISC)
+        ("ISC".to_string(), Instr::ISC),
+        ("JCC".to_string(), Instr::JCC),
+        ("JCS".to_string(), Instr::JCS),
+        ("JEQ".to_string(), Instr::JEQ),
+        ("JMI".to_string(), Instr::JMI),
+        ("JNE".to_string(), Instr::JNE),
+        ("JPL".to_string(), Instr::JPL),
+        ("JVC".to_string(), Instr::JVC),
+        ("JVS".to_string(), Instr::JVS)
     ]);
 
     pub static ref OPCODES: HashMap<(Instr, AdrMode), Vec<Opcode>> = HashMap::from([
@@ -210,7 +259,7 @@ lazy_static! {
             Opcode::new(0xE2, false, vec!["NOP #$BB".to_string()])
         ]),
         ((Instr::ASL, AdrMode::ZP), vec![Opcode::new(0x06, true, vec!["ASL $BB".to_string()])]),
-        ((Instr::ASL, AdrMode::IMPL), vec![Opcode::new(0x0A, true, vec!["ASL".to_string()])]),
+        ((Instr::ASL, AdrMode::ACC), vec![Opcode::new(0x0A, true, vec!["ASL".to_string()])]),
         ((Instr::ASL, AdrMode::ABS), vec![Opcode::new(0x0E, true, vec!["ASL $LLHH".to_string()])]),
         ((Instr::ASL, AdrMode::ZPX), vec![Opcode::new(0x16, true, vec!["ASL $BB,X".to_string()])]),
         ((Instr::ASL, AdrMode::ABSX), vec![Opcode::new(0x1E, true, vec!["ASL $LLHH,X".to_string()])]),
@@ -240,7 +289,7 @@ lazy_static! {
         ((Instr::BIT, AdrMode::ZP), vec![Opcode::new(0x24, true, vec!["BIT $BB".to_string()])]),
         ((Instr::BIT, AdrMode::ABS), vec![Opcode::new(0x2C, true, vec!["BIT $LLHH".to_string()])]),
         ((Instr::ROL, AdrMode::ZP), vec![Opcode::new(0x26, true, vec!["ROL $BB".to_string()])]),
-        ((Instr::ROL, AdrMode::IMPL), vec![Opcode::new(0x2A, true, vec!["ROL".to_string()])]),
+        ((Instr::ROL, AdrMode::ACC), vec![Opcode::new(0x2A, true, vec!["ROL".to_string()])]),
         ((Instr::ROL, AdrMode::ABS), vec![Opcode::new(0x2E, true, vec!["ROL $LLHH".to_string()])]),
         ((Instr::ROL, AdrMode::ZPX), vec![Opcode::new(0x36, true, vec!["ROL $BB,X".to_string()])]),
         ((Instr::ROL, AdrMode::ABSX), vec![Opcode::new(0x3E, true, vec!["ROL $LLHH,X".to_string()])]),
@@ -264,7 +313,7 @@ lazy_static! {
         ((Instr::SRE, AdrMode::ABSY), vec![Opcode::new(0x5B, false, vec!["SRE $LLHH,Y".to_string()])]),
         ((Instr::SRE, AdrMode::ABSX), vec![Opcode::new(0x5F, false, vec!["SRE $LLHH,X".to_string()])]),
         ((Instr::LSR, AdrMode::ZP), vec![Opcode::new(0x46, true, vec!["LSR $BB".to_string()])]),
-        ((Instr::LSR, AdrMode::IMPL), vec![Opcode::new(0x4A, true, vec!["LSR".to_string()])]),
+        ((Instr::LSR, AdrMode::ACC), vec![Opcode::new(0x4A, true, vec!["LSR".to_string()])]),
         ((Instr::LSR, AdrMode::ABS), vec![Opcode::new(0x4E, true, vec!["LSR $LLHH".to_string()])]),
         ((Instr::LSR, AdrMode::ZPX), vec![Opcode::new(0x56, true, vec!["LSR $BB,X".to_string()])]),
         ((Instr::LSR, AdrMode::ABSX), vec![Opcode::new(0x5E, true, vec!["LSR $LLHH,X".to_string()])]),
@@ -291,7 +340,7 @@ lazy_static! {
         ((Instr::RRA, AdrMode::ABSY), vec![Opcode::new(0x7B, false, vec!["RRA $LLHH,Y".to_string()])]),
         ((Instr::RRA, AdrMode::ABSX), vec![Opcode::new(0x7F, false, vec!["RRA $LLHH,X".to_string()])]),
         ((Instr::ROR, AdrMode::ZP), vec![Opcode::new(0x66, true, vec!["ROR $BB".to_string()])]),
-        ((Instr::ROR, AdrMode::IMPL), vec![Opcode::new(0x6A, true, vec!["ROR".to_string()])]),
+        ((Instr::ROR, AdrMode::ACC), vec![Opcode::new(0x6A, true, vec!["ROR".to_string()])]),
         ((Instr::ROR, AdrMode::ABS), vec![Opcode::new(0x6E, true, vec!["ROR $LLHH".to_string()])]),
         ((Instr::ROR, AdrMode::ZPX), vec![Opcode::new(0x76, true, vec!["ROR $BB,X".to_string()])]),
         ((Instr::ROR, AdrMode::ABSX), vec![Opcode::new(0x7E, true, vec!["ROR $LLHH,X".to_string()])]),
@@ -415,3 +464,43 @@ lazy_static! {
         ((Instr::SED, AdrMode::IMPL), vec![Opcode::new(0xF8, true, vec!["SED".to_string()])]),
     ]);
 }
+
+/// Branch taken on the opposite condition
+pub fn inverted_branch(instr: &Instr) -> Option<Instr> {
+    match instr {
+        Instr::BCC => Some(Instr::BCS),
+        Instr::BCS => Some(Instr::BCC),
+        Instr::BEQ => Some(Instr::BNE),
+        Instr::BNE => Some(Instr::BEQ),
+        Instr::BMI => Some(Instr::BPL),
+        Instr::BPL => Some(Instr::BMI),
+        Instr::BVC => Some(Instr::BVS),
+        Instr::BVS => Some(Instr::BVC),
+        _ => None
+    }
+}
+
+/// Branch behind a pseudo instruction: JEQ is BEQ, relaxed when out of reach
+pub fn long_branch(instr: &Instr) -> Option<Instr> {
+    match instr {
+        Instr::JCC => Some(Instr::BCC),
+        Instr::JCS => Some(Instr::BCS),
+        Instr::JEQ => Some(Instr::BEQ),
+        Instr::JMI => Some(Instr::BMI),
+        Instr::JNE => Some(Instr::BNE),
+        Instr::JPL => Some(Instr::BPL),
+        Instr::JVC => Some(Instr::BVC),
+        Instr::JVS => Some(Instr::BVS),
+        _ => None
+    }
+}
+
+/// Zero page counterpart of an absolute mode
+pub fn zero_page_mode(mode: &AdrMode) -> Option<AdrMode> {
+    match mode {
+        AdrMode::ABS => Some(AdrMode::ZP),
+        AdrMode::ABSX => Some(AdrMode::ZPX),
+        AdrMode::ABSY => Some(AdrMode::ZPY),
+        _ => None
+    }
+}
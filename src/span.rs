@@ -0,0 +1,153 @@
+use std::{fmt, sync::Arc};
+
+/// Source code shared by every span pointing into it
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SourceFile {
+    pub name: String,
+    pub text: String,
+    /// Location of the .include directive that pulled this file
+    pub included_from: Option<Span>
+}
+
+impl SourceFile {
+    pub fn new(name: &str, text: &str) -> Arc<Self> {
+        Arc::new(Self {
+            name: name.to_string(),
+            text: text.to_string(),
+            included_from: None
+        })
+    }
+
+    pub fn included(name: &str, text: &str, from: Span) -> Arc<Self> {
+        Arc::new(Self {
+            name: name.to_string(),
+            text: text.to_string(),
+            included_from: Some(from)
+        })
+    }
+
+    /// Files including this one, innermost first
+    pub fn include_stack(&self) -> Vec<Span> {
+        let mut stack = vec![];
+        let mut from = self.included_from.clone();
+        while let Some(span) = from {
+            from = span.file.included_from.clone();
+            stack.push(span);
+        }
+        stack
+    }
+}
+
+/// Macro call a token was copied from
+#[derive(Debug, PartialEq, Eq)]
+pub struct Expansion {
+    /// Name of the macro called
+    pub name: String,
+    /// Location of the call
+    pub call: Span
+}
+
+/// Location of a token or an expression in a source file
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Span {
+    pub file: Arc<SourceFile>,
+    /// 1-based line
+    pub line: usize,
+    /// 1-based column, in characters
+    pub column: usize,
+    /// Byte range in the source text
+    pub start: usize,
+    pub end: usize,
+    /// Set when the token comes from the body of a macro
+    pub expanded_from: Option<Arc<Expansion>>
+}
+
+impl Span {
+    /// Span going from the start of `self` to the end of `other`
+    pub fn to(&self, other: &Span) -> Span {
+        Span {
+            end: other.end.max(self.end),
+            ..self.clone()
+        }
+    }
+
+    /// Macro calls the span was expanded from, innermost first
+    pub fn expansion_stack(&self) -> Vec<Arc<Expansion>> {
+        let mut stack = vec![];
+        let mut from = self.expanded_from.clone();
+        while let Some(expansion) = from {
+            from = expansion.call.expanded_from.clone();
+            stack.push(expansion);
+        }
+        stack
+    }
+
+    /// Render `message` along with the spanned source line, rustc-style
+    /// ```text
+    /// error: token NEWLINE unexpected
+    ///  --> prog.asm:3:10
+    ///   |
+    /// 3 |     LDA #
+    ///   |          ^
+    ///   = note: included from main.asm:12:5
+    ///   = note: in expansion of macro m at main.asm:20:5
+    /// ```
+    pub fn render(&self, message: &str) -> String {
+        self.render_as("error", message)
+    }
+
+    /// Same as `render` with another severity: warning, note, ...
+    pub fn render_as(&self, level: &str, message: &str) -> String {
+        let mut out = format!("{}: {}", level, message);
+        if self.line == 0 {
+            // no location, e.g. tokens built by hand
+            return out;
+        }
+        let text = &self.file.text;
+        let line_start = text[..self.start.min(text.len())]
+            .rfind('\n')
+            .map(|pos| pos + 1)
+            .unwrap_or(0);
+        let line_end = text[line_start..]
+            .find(['\n', '\r'])
+            .map(|pos| line_start + pos)
+            .unwrap_or(text.len());
+        let source_line = &text[line_start..line_end];
+        let end = self.end.clamp(self.start, line_end);
+        let width = text[self.start.min(end)..end].chars().count().max(1);
+
+        let gutter = " ".repeat(self.line.to_string().len());
+        let padding: String = source_line
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        out.push_str(&format!("\n{}--> {:?}", gutter, self));
+        out.push_str(&format!("\n{} |", gutter));
+        out.push_str(&format!("\n{} | {}", self.line, source_line));
+        out.push_str(&format!("\n{} | {}{}", gutter, padding, "^".repeat(width)));
+        for span in self.file.include_stack() {
+            out.push_str(&format!("\n{} = note: included from {:?}", gutter, span));
+        }
+        for expansion in self.expansion_stack() {
+            out.push_str(&format!(
+                "\n{} = note: in expansion of macro {} at {:?}",
+                gutter, expansion.name, expansion.call
+            ));
+        }
+        out
+    }
+}
+
+impl fmt::Debug for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file.name, self.line, self.column)
+    }
+}
+
+/// A value with its location in the source code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Spanned<T> {
+    pub value: T,
+    pub span: Span
+}
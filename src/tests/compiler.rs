@@ -1,6 +1,26 @@
 use std::cell::RefCell;
+use std::fs;
 
 use crate::compiler::{Compiler, CompilerConfig};
+use crate::error::AsmError;
+use crate::opcodes::{AdrMode, Instr, Stability};
+
+/// Tells whether an error is the expected one
+type ErrorCheck = fn(&AsmError) -> bool;
+
+/// Compile each source, it must fail with an error accepted by the check along
+fn assert_errors(cases: &[(&str, ErrorCheck)]) {
+    for (source, expected) in cases {
+        let mut compiler = Compiler::new(None);
+        let res = compiler
+            .init_source(source)
+            .and_then(|_| compiler.to_byte_code());
+        match res {
+            Ok(_) => panic!("{:?} should not compile", source),
+            Err(e) => assert!(expected(&e), "{:?} fails with {}", source, e)
+        }
+    }
+}
 
 #[test]
 fn simple_compilation() {
@@ -42,7 +62,8 @@ fn compile_illegal() {
         enable_nes: false,
         allow_list: RefCell::new(vec![
             0xDA // non official op
-        ])
+        ]),
+        ..Default::default()
     }));
     compiler.init_source(&source).unwrap();
     let hex_string = compiler.to_hex_string().unwrap();
@@ -53,22 +74,108 @@ fn compile_illegal() {
     ]);
 }
 
+#[test]
+fn illegal_opcode_control() {
+    let mut compiler = Compiler::new(None);
+    compiler.init_source("NOP\nLAX #$0a").unwrap();
+    match compiler.to_byte_code() {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => {
+            assert!(matches!(&e, AsmError::ILLEGAL { instr: Instr::LAX, mode: AdrMode::IMM, span } if span.line == 2));
+            assert!(e.to_string().starts_with("error: instruction (LAX, IMM) is unofficial"));
+        }
+    }
+
+    let source =String::from(r##"
+        .illegal on
+            LAX #$0a            ; ab 0a
+        .ILLEGAL off
+            NOP                 ; ea
+    "##);
+    let mut compiler = Compiler::new(Some(CompilerConfig {
+        allow_list: RefCell::new(vec![0xDA]),
+        ..Default::default()
+    }));
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "ab 0a ea");
+
+    // allowed by the config, but not in the source
+    let mut compiler = Compiler::new(Some(CompilerConfig {
+        allow_illegal: true,
+        ..Default::default()
+    }));
+    compiler.init_source("LAX #1\n.illegal off\nLAX #1").unwrap();
+    assert!(matches!(compiler.to_byte_code(), Err(AsmError::ILLEGAL { span, .. }) if span.line == 3));
+
+    assert_errors(&[(".illegal maybe", |e| matches!(e, AsmError::PARSE { .. }))]);
+}
+
+#[test]
+fn opcode_stability() {
+    let source =String::from(r##"
+        LAX #$0a            ; ab 0a, highly unstable
+        SHY $1234,x         ; 9c 34 12, unstable
+        SLO $10             ; 07 10
+    "##);
+    let mut compiler = Compiler::new(Some(CompilerConfig {
+        allow_illegal: true,
+        ..Default::default()
+    }));
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "ab 0a 9c 34 12 07 10");
+    let warnings = compiler.warnings();
+    assert_eq!(warnings.len(), 2);
+    assert_eq!(warnings[0].span.line, 2);
+    assert!(warnings[0].to_string().starts_with("warning: instruction (LAX, IMM) is highly unstable"));
+    assert!(warnings[1].message.contains("(SHY, ABSX) is unstable"));
+
+    let mut compiler = Compiler::new(Some(CompilerConfig {
+        allow_illegal: true,
+        strict: true,
+        ..Default::default()
+    }));
+    compiler.init_source(&source).unwrap();
+    assert!(matches!(
+        compiler.to_byte_code(),
+        Err(AsmError::UNSTABLE { stability: Stability::HIGHLYUNSTABLE, span, .. }) if span.line == 2
+    ));
+
+    // jamming opcodes need their own permission
+    let mut compiler = Compiler::new(Some(CompilerConfig {
+        allow_illegal: true,
+        ..Default::default()
+    }));
+    compiler.init_source("STP").unwrap();
+    assert!(matches!(compiler.to_byte_code(), Err(AsmError::UNSTABLE { stability: Stability::JAM, .. })));
+
+    let mut compiler = Compiler::new(Some(CompilerConfig {
+        allow_illegal: true,
+        allow_jam: true,
+        ..Default::default()
+    }));
+    compiler.init_source("STP").unwrap();
+    assert_eq!(compiler.to_hex_string().unwrap(), "02");
+    assert!(compiler.warnings().is_empty());
+}
+
 #[test]
 fn jump_ahead() {
     let source =String::from(r##"
         LDA #$00
-        BNE my_label ; d0 08 
+        BNE my_label ; d0 07, relative to the next instruction
         ADC #$02
         TAX
         CPX #100
-        BNE $02
+        BNE $02      ; d0 f7, back to ADC
         my_label:
         NOP
     "##);
     let mut compiler = Compiler::new(None);
     compiler.init_source(&source).unwrap();
     let hex_string = compiler.to_hex_string().unwrap();
-    assert_eq!(hex_string, "a9 00 d0 08 69 02 aa e0 64 d0 02 ea");
+    assert_eq!(hex_string, "a9 00 d0 07 69 02 aa e0 64 d0 f7 ea");
 }
 
 #[test]
@@ -79,14 +186,14 @@ fn jump_behind() {
         ADC #$02
         TAX
         CPX #100
-        BNE $02
-        BNE my_label ; d0 -$08 == d0 $f7 (after cast to unsigned)
+        BNE $02      ; d0 -$07 == d0 $f9, back to ADC
+        BNE my_label ; d0 -$09 == d0 $f7 (after cast to unsigned)
         NOP
     "##);
     let mut compiler = Compiler::new(None);
     compiler.init_source(&source).unwrap();
     let hex_string = compiler.to_hex_string().unwrap();
-    assert_eq!(hex_string, "a9 00 69 02 aa e0 64 d0 02 d0 f7 ea");
+    assert_eq!(hex_string, "a9 00 69 02 aa e0 64 d0 f9 d0 f7 ea");
 }
 
 
@@ -96,7 +203,7 @@ fn mode_and_math_expansion() {
         ; zp => ASL $BB => 06 ae
         ASL $aa + 2 * %010
 
-        ; impl => ASL => 0a
+        ; acc => ASL => 0a
         ASL
 
         ; abs => ASL $LLHH => 0e aa bb
@@ -126,6 +233,1013 @@ fn mode_and_math_expansion() {
     compiler.init_source(&source).unwrap();
     match compiler.to_hex_string() {
         Ok(_) => panic!("error was expected"),
-        Err(s) => assert_eq!(s, "instruction (ASL, INDY) does not exist")
+        Err(e) => assert_eq!(e.to_string(), [
+            "error: instruction (ASL, INDY) does not exist",
+            " --> <source>:5:9",
+            "  |",
+            "5 |         ASL ($aa + 2 * %010), y",
+            "  |         ^^^^^^^^^^^^^^^^^^^^^^^",
+        ].join("\n"))
+    }
+}
+#[test]
+fn label_operands() {
+    let source =String::from(r##"
+        JSR init            ; 20 06 00
+        JMP reset           ; 4c 0b 00
+        init:
+        LDA table+1,X       ; b5 0e (zero page)
+        STA buffer-2        ; 85 0d
+        RTS
+        reset:
+        LDA #reset          ; a9 0b
+        table:
+        .byte 1, 2
+        buffer:
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "20 06 00 4c 0b 00 b5 0e 85 0d 60 a9 0b 01 02");
+
+    let source =String::from(r##"
+        JMP nowhere
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    match compiler.to_hex_string() {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => assert_eq!(e.to_string(), [
+            "error: variable \"nowhere\" is undefined",
+            " --> <source>:2:9",
+            "  |",
+            "2 |         JMP nowhere",
+            "  |         ^^^^^^^^^^^",
+        ].join("\n"))
+    }
+}
+
+#[test]
+fn many_jumps_same_label() {
+    let source =String::from(r##"
+        start:
+        BEQ done        ; f0 04
+        BNE done        ; d0 02
+        BCC start       ; 90 fa
+        done:
+        BCS start       ; b0 f8
+        JMP done        ; 4c 06 00
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "f0 04 d0 02 90 fa b0 f8 4c 06 00");
+
+    // no stale label from the previous source
+    let source =String::from(r##"
+        BNE done
+    "##);
+    compiler.init_source(&source).unwrap();
+    match compiler.to_hex_string() {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => assert!(matches!(e, AsmError::UNDEFINED { name, .. } if name == "done"))
+    }
+}
+
+#[test]
+fn origin() {
+    let source =String::from(r##"
+        .org $c000
+        reset:
+        LDX #$00
+        loop:
+        INX
+        BNE $c002       ; d0 fd
+        JMP reset       ; 4c 00 c0
+        .org $c010
+        handler:
+        JMP handler     ; 4c 10 c0
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "a2 00 e8 d0 fd 4c 00 c0 4c 10 c0");
+
+    let source =String::from(r##"
+        start:
+        BEQ start       ; f0 fe
+        JSR start       ; 20 01 08
+    "##);
+    let mut compiler = Compiler::new(Some(CompilerConfig {
+        origin: 0x0801,
+        ..Default::default()
+    }));
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "f0 fe 20 01 08");
+
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(".ORG $10\n.BYTE 1\n.Res 1\nNOP").unwrap();
+    assert_eq!(compiler.to_hex_string().unwrap(), "01 00 ea");
+
+    // the last NOP would be at $10000
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(".org $fffe\nNOP\nNOP\nNOP").unwrap();
+    assert!(matches!(compiler.to_byte_code(), Err(AsmError::RANGE { span, .. }) if span.line == 4));
+}
+
+#[test]
+fn no_panic_on_invalid_input() {
+    assert_errors(&[
+        ("LDA 123456", |e| matches!(e, AsmError::RANGE { .. })),
+        ("LDA #$1234", |e| matches!(e, AsmError::RANGE { .. })),
+        ("LDA ($1234), y", |e| matches!(e, AsmError::RANGE { .. })),
+        (".byte 'ÿ' + 1", |e| matches!(e, AsmError::RANGE { .. })),
+        (".res 99999999999999999999999", |e| matches!(e, AsmError::RANGE { .. })),
+        ("LDA #((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((((1", |e| matches!(e, AsmError::PARSE { .. })),
+        (". byte 1", |e| matches!(e, AsmError::LEX { .. })),
+        ("LDA #1 / 0", |e| matches!(e, AsmError::MATH { .. })),
+        ("BNE $1234", |e| matches!(e, AsmError::RANGE { .. })),
+    ]);
+
+    let mut compiler = Compiler::new(None);
+    compiler.init_source("ASL ($aa), y").unwrap();
+    match compiler.to_byte_code() {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => assert!(matches!(e, AsmError::OPCODE { instr: Instr::ASL, mode: AdrMode::INDY, .. }))
+    }
+
+    let mut compiler = Compiler::new(None);
+    match compiler.init("./does/not/exist.asm") {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => assert!(matches!(e, AsmError::IO { .. }))
+    }
+}
+
+#[test]
+fn macros() {
+    let source =String::from(r##"
+        .macro add16 dest, value
+            CLC
+            LDA dest
+            ADC #value
+            STA dest
+            BCC skip
+            INC dest + 1
+            skip:
+        .endmacro
+        .macro twice dest value
+            add16 dest, value
+            add16 dest, value
+        .endmacro
+
+        ptr = $10
+        twice ptr, $20      ; 18 a5 10 69 20 85 10 90 02 e6 11 (x2)
+        done: add16 $0300, 1
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "18 a5 10 69 20 85 10 90 02 e6 11 \
+                            18 a5 10 69 20 85 10 90 02 e6 11 \
+                            18 ad 00 03 69 01 8d 00 03 90 03 ee 01 03");
+
+    // anonymous labels are not renamed, references to them are not labels
+    let source =String::from(r##"
+        .macro wait
+            : DEX
+            BNE :-
+        .endmacro
+        wait                ; ca d0 fd
+        wait                ; ca d0 fd
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "ca d0 fd ca d0 fd");
+
+    // diagnostics name the labels of a body as they are written
+    let source =String::from(r##"
+        .macro m
+            @l: NOP
+            first:
+            BNE @l
+        .endmacro
+        m
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    match compiler.to_byte_code() {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => {
+            assert!(matches!(&e, AsmError::UNDEFINED { name, scope: Some(scope), .. } if name == "@l" && scope == "first"));
+            assert!(e.to_string().starts_with("error: local label \"@l\" is undefined in scope \"first\""));
+        }
+    }
+
+    // errors in a body point to the body, then to each call
+    let source =String::from(r##"
+.macro load value
+    LDA #value
+.endmacro
+.macro init
+    load $1234
+.endmacro
+    init
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    match compiler.to_byte_code() {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => {
+            let rendered = e.to_string();
+            assert!(matches!(e, AsmError::RANGE { span, .. } if span.line == 3));
+            assert!(rendered.ends_with([
+                "  = note: in expansion of macro load at <source>:6:5",
+                "  = note: in expansion of macro init at <source>:8:5",
+            ].join("\n").as_str()), "{}", rendered);
+        }
+    }
+
+    assert_errors(&[
+        (".macro m\n NOP\n", |e| matches!(e, AsmError::DIRECTIVE { .. })),
+        (".endmacro", |e| matches!(e, AsmError::DIRECTIVE { .. })),
+        (".macro m a\n NOP\n.endmacro\n m", |e| matches!(e, AsmError::PARSE { .. })),
+        (".macro m\n m\n.endmacro\n m", |e| matches!(e, AsmError::DIRECTIVE { .. })),
+        (".macro lda\n NOP\n.endmacro", |e| matches!(e, AsmError::SYMBOL { .. })),
+        (".macro m\n.macro n\n.endmacro\n.endmacro", |e| matches!(e, AsmError::DIRECTIVE { .. })),
+    ]);
+}
+
+#[test]
+fn include() {
+    let root = std::env::temp_dir().join(format!("r6502_include_{}", std::process::id()));
+    fs::create_dir_all(root.join("src")).unwrap();
+    fs::create_dir_all(root.join("lib")).unwrap();
+    fs::write(root.join("src/main.asm"), ".include \"consts.asm\"\n.include \"util.asm\"\nLDA #VALUE\nincx\n").unwrap();
+    fs::write(root.join("src/consts.asm"), "VALUE = $2a\n").unwrap();
+    fs::write(root.join("lib/util.asm"), ".macro incx\n    INX\n.endmacro\n").unwrap();
+    fs::write(root.join("src/a.asm"), "NOP\n.include \"b.asm\"\n").unwrap();
+    fs::write(root.join("src/b.asm"), ".include \"a.asm\"\n").unwrap();
+    fs::write(root.join("src/bad.asm"), "\n  .include \"../lib/broken.asm\"\n").unwrap();
+    fs::write(root.join("lib/broken.asm"), "LDA #\n").unwrap();
+
+    let mut compiler = Compiler::new(Some(CompilerConfig {
+        include_dirs: vec![root.join("lib")],
+        ..Default::default()
+    }));
+    compiler.init(root.join("src/main.asm")).unwrap();
+    assert_eq!(compiler.to_hex_string().unwrap(), "a9 2a e8");
+
+    // not found without the include directory
+    let mut compiler = Compiler::new(None);
+    match compiler.init(root.join("src/main.asm")) {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => assert!(matches!(e, AsmError::IO { path, .. } if path == "util.asm"))
+    }
+
+    let mut compiler = Compiler::new(None);
+    match compiler.init(root.join("src/a.asm")) {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => {
+            assert!(matches!(e, AsmError::DIRECTIVE { .. }));
+            assert!(e.to_string().contains("note: included from"));
+        }
+    }
+
+    let mut compiler = Compiler::new(None);
+    match compiler.init(root.join("src/bad.asm")) {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => {
+            let rendered = e.to_string();
+            assert!(rendered.contains("broken.asm:1:6"), "{}", rendered);
+            assert!(rendered.contains("= note: included from "), "{}", rendered);
+            assert!(rendered.contains("bad.asm:2:3"), "{}", rendered);
+        }
+    }
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn incbin() {
+    let root = std::env::temp_dir().join(format!("r6502_incbin_{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("tiles.chr"), [0x00, 0x11, 0x22, 0x33, 0x44, 0x55]).unwrap();
+    let cases = [
+        (".incbin \"tiles.chr\"\nend: JMP end", "00 11 22 33 44 55 4c 06 00"),
+        (".incbin \"tiles.chr\", 2\n", "22 33 44 55"),
+        (".incbin \"tiles.chr\", 1, 2\nNOP", "11 22 ea"),
+        (".incbin \"tiles.chr\", 6, 0\nNOP", "ea"),
+        (".INCBIN \"tiles.chr\", 5\n", "55"),
+    ];
+    for (source, expected) in cases {
+        let path = root.join("main.asm");
+        fs::write(&path, source).unwrap();
+        let mut compiler = Compiler::new(None);
+        compiler.init(&path).unwrap();
+        assert_eq!(compiler.to_hex_string().unwrap(), expected, "{:?}", source);
+    }
+
+    let chr = root.join("tiles.chr").display().to_string();
+    assert_errors(&[
+        (&format!(".incbin {:?}, 7", chr), |e| matches!(e, AsmError::RANGE { .. })),
+        (&format!(".incbin {:?}, 4, 3", chr), |e| matches!(e, AsmError::RANGE { .. })),
+        (".incbin \"missing.chr\"", |e| matches!(e, AsmError::IO { .. })),
+    ]);
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn conditional_assembly() {
+    let source =String::from(r##"
+        PAL = 1
+        DEBUG = 0
+        .if PAL
+            LDA #50
+        .elseif DEBUG
+            LDA #1
+        .else
+            LDA #60
+        .endif
+        .if DEBUG               ; nothing here is parsed
+            .if UNDEFINED_SYMBOL
+                garbage here
+            .endif
+        .elseif PAL - 1
+            LDX #1
+        .elseif PAL + 1
+            LDX #2              ; a2 02
+            .ifndef DEBUG
+                LDX #3
+            .else
+                .ifdef start
+                    LDY #4      ; start is declared below
+                .endif
+            .endif
+        .else
+            LDX #5
+        .endif
+        start:
+        .ifdef later
+            NOP
+        .endif
+        later:
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "a9 32 a2 02");
+
+    // includes and macros of skipped blocks are left alone
+    let source =String::from(r##"
+        .if 0
+            .include "missing.asm"
+        .endif
+        .ifdef FAST
+            .macro wait
+                NOP
+            .endmacro
+        .else
+            .macro wait
+                NOP
+                NOP
+            .endmacro
+        .endif
+        .macro countdown n
+            .if n
+                .byte n
+                countdown n - 1
+            .endif
+        .endmacro
+        wait                    ; ea ea
+        countdown 3             ; 03 02 01
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "ea ea 03 02 01");
+
+    let mut compiler = Compiler::new(None);
+    match compiler.init_source("NOP\n  .if 1\n.if 0\n.endif\nNOP") {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => assert_eq!(e.to_string(), [
+            "error: conditional block is never closed by .endif",
+            " --> <source>:2:3",
+            "  |",
+            "2 |   .if 1",
+            "  |   ^^^",
+        ].join("\n"))
+    }
+
+    assert_errors(&[
+        (".endif", |e| matches!(e, AsmError::DIRECTIVE { .. })),
+        (".else", |e| matches!(e, AsmError::DIRECTIVE { .. })),
+        (".if 1\n.else\n.else\n.endif", |e| matches!(e, AsmError::DIRECTIVE { .. })),
+        (".if 1\n.else\n.elseif 1\n.endif", |e| matches!(e, AsmError::DIRECTIVE { .. })),
+        (".if later\n.endif\nlater:", |e| matches!(e, AsmError::DIRECTIVE { .. })),
+        (".if 1 NOP\n.endif", |e| matches!(e, AsmError::PARSE { .. })),
+    ]);
+}
+
+#[test]
+fn repeat_blocks() {
+    let source =String::from(r##"
+        SIZE = 4
+        table:
+        .repeat SIZE, i
+            .byte i * 3
+        .endrepeat
+        .repeat 2
+            .repeat 3, j        ; inner counter
+                .byte j
+            .endrepeat
+            NOP
+        .endrepeat
+        .repeat 0
+            .byte $ff
+        .endrepeat
+        .repeat 2, row
+            .if row
+                LDA #row
+            .endif
+        .endrepeat
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "00 03 06 09 00 01 02 ea 00 01 02 ea a9 01");
+
+    // the counter is a variable, registers and inner counters keep their name
+    let source =String::from(r##"
+        .repeat 2, x
+            LDA table + x,x         ; b5 0c, b5 0d
+        .endrepeat
+        .repeat 2, i
+            .repeat 2, i
+                .byte i             ; 00 01, 00 01
+            .endrepeat
+            .word table + i         ; 0c 00, 0d 00
+        .endrepeat
+        table:
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "b5 0c b5 0d 00 01 0c 00 00 01 0d 00");
+
+    assert_errors(&[
+        (".repeat 2\nNOP", |e| matches!(e, AsmError::DIRECTIVE { .. })),
+        (".endrepeat", |e| matches!(e, AsmError::DIRECTIVE { .. })),
+        (".ENDREP", |e| matches!(e, AsmError::DIRECTIVE { .. })),
+        (".repeat label\nNOP\n.endrepeat\nlabel:", |e| matches!(e, AsmError::DIRECTIVE { .. })),
+        (".repeat 2\nlabel:\n.endrepeat", |e| matches!(e, AsmError::SYMBOL { .. })),
+        (".repeat $ffff\n.repeat $ffff\nNOP\n.endrepeat\n.endrepeat", |e| matches!(e, AsmError::RANGE { .. })),
+        ("x = lbl\n.repeat 2, x\n.byte x\n.endrepeat\nlbl:", |e| matches!(e, AsmError::SYMBOL { .. })),
+    ]);
+}
+
+#[test]
+fn expression_operators() {
+    let cases = [
+        (".byte 10 - 3 - 2", "05"),
+        (".byte 16 / 4 / 2", "02"),
+        (".byte 2 + 3 * 4, (2 + 3) * 4", "0e 14"),
+        (".byte 17 % 5, 17%5, %101", "02 02 05"),
+        (".byte $f0 & $3c | 1, $f0 ^ $ff, ~$0f", "31 0f f0"),
+        (".byte 1 << 4, $80 >> 3, 1 + 1 << 2", "10 10 08"),
+        (".byte 3 < 4, 3 > 4, 4 <= 4, 5 >= 6, 2 = 2, 2 <> 2, 2 != 3", "01 00 01 00 01 00 01"),
+        (".byte 1 && 0, 1 || 0, !0, !5, 1 < 2 && 2 < 3", "00 01 01 00 01"),
+        (".byte <$1234, >$1234, >$1234 + 1", "34 12 13"),
+        (".dw -1, ~0, ~$0000", "ff ff ff ff ff ff"),
+        ("LDA #<message\nLDX #>message\n.org $c0fe\nmessage:", "a9 fe a2 c0"),
+    ];
+    for (source, expected) in cases {
+        let mut compiler = Compiler::new(None);
+        compiler.init_source(source).unwrap();
+        assert_eq!(compiler.to_hex_string().unwrap(), expected, "{:?}", source);
+    }
+
+    assert_errors(&[
+        (".byte 1 % 0", |e| matches!(e, AsmError::MATH { .. })),
+        (".dw $ff << 9", |e| matches!(e, AsmError::RANGE { .. })),
+        (&format!(".byte 0{}", "+0".repeat(200)), |e| matches!(e, AsmError::PARSE { .. })),
+        (&format!(".byte {}1", "-".repeat(200)), |e| matches!(e, AsmError::PARSE { .. })),
+    ]);
+}
+
+#[test]
+fn wide_arithmetic() {
+    let cases = [
+        (".byte $10 - $20 + $30", "20"),
+        (".byte -1, -128, 300 - 200", "ff 80 64"),
+        (".dw -1, -32768, $ffff * $ffff / $ffff", "ff ff 00 80 ff ff"),
+        ("LDA #-1\nLDX #>-2\nLDY #-$80", "a9 ff a2 ff a0 80"),
+        ("LDA $20 - $18\nLDA $0100 - 1", "a5 08 ad ff 00"),
+        ("OFFSET = -2\nLDA $10 + OFFSET, x", "b5 0e"),
+        ("JMP $1000 * 16 - 1", "4c ff ff"),
+    ];
+    for (source, expected) in cases {
+        let mut compiler = Compiler::new(None);
+        compiler.init_source(source).unwrap();
+        assert_eq!(compiler.to_hex_string().unwrap(), expected, "{:?}", source);
+    }
+
+    assert_errors(&[
+        (".byte -129", |e| matches!(e, AsmError::RANGE { .. })),
+        (".byte 256", |e| matches!(e, AsmError::RANGE { .. })),
+        (".dw 65536", |e| matches!(e, AsmError::RANGE { .. })),
+        ("LDA #-129", |e| matches!(e, AsmError::RANGE { .. })),
+        ("LDA -1", |e| matches!(e, AsmError::RANGE { .. })),
+        ("JMP $1000 * 16", |e| matches!(e, AsmError::RANGE { .. })),
+        ("JMP -1", |e| matches!(e, AsmError::RANGE { .. })),
+        (".org -1", |e| matches!(e, AsmError::RANGE { .. })),
+        ("LDA #label - $0100\nlabel:", |e| matches!(e, AsmError::RANGE { .. })),
+        ("JMP label - $0100\nlabel:", |e| matches!(e, AsmError::RANGE { .. })),
+        (".byte 9223372036854775807 + 1", |e| matches!(e, AsmError::MATH { .. })),
+    ]);
+}
+
+#[test]
+fn program_counter() {
+    let source =String::from(r##"
+        .org $8000
+        JMP *               ; 4c 00 80
+        BNE * + 4           ; d0 02
+        NOP
+        NOP
+        table:
+        .byte 1, 2, 3
+        table_end = *
+        LDA #table_end - table      ; a9 03
+        LDX #* * 2 & $ff            ; a2 18
+        JMP table_end + 1           ; 4c 0b 80
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "4c 00 80 d0 02 ea ea 01 02 03 a9 03 a2 18 4c 0b 80");
+
+    // each use sees the assignment before it, or the last one when none
+    let source =String::from(r##"
+        .word here, after       ; 09 00 0a 00
+        here = *
+        after = here + 1
+        .word here, after       ; 04 00 05 00
+        NOP
+        here = *
+        after = here + 1
+        .word here, after       ; 09 00 0a 00
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "09 00 0a 00 04 00 05 00 ea 09 00 0a 00");
+
+    // sizes are chosen from the captured value too
+    let source =String::from(r##"
+        .org $1000
+        here = *
+            LDA here            ; ad 00 10
+            BEQ here            ; f0 fb
+        .org $10
+        here = *
+    "##);
+    let mut compiler = Compiler::new(Some(CompilerConfig {
+        relax_branches: true,
+        ..Default::default()
+    }));
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "ad 00 10 f0 fb");
+
+    let mut compiler = Compiler::new(None);
+    match compiler.init_source(".if * > 0\n.endif") {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => assert!(matches!(e, AsmError::DIRECTIVE { .. }))
+    }
+}
+
+#[test]
+fn local_labels() {
+    let source =String::from(r##"
+        clear:
+            LDX #$08
+        @loop:
+            DEX
+            BNE @loop           ; d0 fd
+            BEQ .done           ; f0 00
+        .done:
+            RTS
+        draw:
+            LDY #$08
+        @loop:
+            DEY
+            BNE @loop           ; d0 fd
+            JMP .done           ; 4c 10 00
+        .done:
+            JMP @loop           ; 4c 0a 00
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "a2 08 ca d0 fd f0 00 60 a0 08 88 d0 fd 4c 10 00 4c 0a 00");
+
+    let mut compiler = Compiler::new(None);
+    compiler.init_source("first:\n@skip:\nsecond:\nBNE @skip").unwrap();
+    match compiler.to_byte_code() {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => {
+            assert!(matches!(&e, AsmError::UNDEFINED { name, scope: Some(scope), .. } if name == "@skip" && scope == "second"));
+            assert!(e.to_string().starts_with("error: local label \"@skip\" is undefined in scope \"second\""));
+        }
+    }
+
+    // a local label does not clash with a global label containing a dot
+    let mut compiler = Compiler::new(None);
+    compiler.init_source("a:\n.b: NOP\nJMP .b\na.b:").unwrap();
+    assert_eq!(compiler.to_hex_string().unwrap(), "ea 4c 00 00");
+
+    // local labels of a macro body are unique to each expansion
+    let source =String::from(r##"
+        .macro m
+            .loop: DEX
+            BNE .loop
+            @next: INY
+            BNE @next
+        .endmacro
+        start:
+            m               ; ca d0 fd c8 d0 fd
+            m               ; ca d0 fd c8 d0 fd
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    assert_eq!(compiler.to_hex_string().unwrap(), "ca d0 fd c8 d0 fd ca d0 fd c8 d0 fd");
+
+    let mut compiler = Compiler::new(None);
+    compiler.init_source("main:\n@a:\n@a:").unwrap();
+    match compiler.to_byte_code() {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => assert!(matches!(e, AsmError::SYMBOL { .. }))
+    }
+}
+
+#[test]
+fn reserve() {
+    let source =String::from(r##"
+        MAX_OBJECTS = 3
+            .res 2
+            .res $02, $ff
+            .res MAX_OBJECTS*2, -1
+        table:
+            LDA table           ; a5 0a
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "00 00 ff ff ff ff ff ff ff ff a5 0a");
+
+    // nothing is stored for uninitialised segments
+    let source =String::from(r##"
+        .segment "HEADER"
+            .byte 1
+        .segment "BSS"
+        buffer:
+            .res 4
+        .segment "CODE"
+            LDA buffer          ; a5 01
+    "##);
+    let mut compiler = Compiler::new(Some(CompilerConfig {
+        enable_nes: true,
+        ..Default::default()
+    }));
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "01 a5 01");
+
+    // sizes are evaluated where the directive is, constants can come later
+    let source =String::from(r##"
+        .org $80fd
+            .byte 1
+            .res $100 - (* & $ff), $ea
+        page:
+            .res COUNT
+            JMP page                ; 4c 00 81
+        COUNT = 2
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "01 ea ea 00 00 4c 00 81");
+
+    assert_errors(&[
+        (".org $fff0\n.res 32", |e| matches!(e, AsmError::RANGE { .. })),
+        (".res 1, 256", |e| matches!(e, AsmError::RANGE { .. })),
+        (".res later\nlater:", |e| matches!(e, AsmError::UNDEFINED { .. })),
+        (".res -1", |e| matches!(e, AsmError::RANGE { .. })),
+        ("NOP\n.res 0 - *", |e| matches!(e, AsmError::RANGE { .. })),
+    ]);
+}
+
+#[test]
+fn data_layout() {
+    let source =String::from(r##"
+            .org $80fe
+            NOP
+            .ALIGN $100         ; 00
+        table:
+            .byte 1
+            .Fill 3, $aa
+            .align 4, $ff       ; already aligned
+            .byte 2
+            .PAD $8108, $ea     ; ea ea ea
+            LDA table,x         ; bd 00 81
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "ea 00 01 aa aa aa 02 ea ea ea bd 00 81");
+
+    assert_errors(&[
+        (".org $10\n.pad $08", |e| matches!(e, AsmError::RANGE { .. })),
+        (".align 0", |e| matches!(e, AsmError::RANGE { .. })),
+        (".fill 3", |e| matches!(e, AsmError::PARSE { .. })),
+        (".pad $10000", |e| matches!(e, AsmError::RANGE { .. })),
+        (".org $ffff\n.fill 2, 0", |e| matches!(e, AsmError::RANGE { .. })),
+    ]);
+}
+
+#[test]
+fn data_tables() {
+    let source =String::from(r##"
+            .org $8000
+        dispatch:
+            LDA hi,x            ; bd 0b 80
+            PHA
+            LDA lo,x            ; bd 09 80
+            PHA
+            RTS
+        lo: .LOBYTES handler_a-1, handler_b-1
+        hi: .HiBytes handler_a-1, handler_b-1
+        handler_a:
+            RTS
+        handler_b:
+            RTS
+            .word handler_a, handler_b, $1234
+            .DBYT handler_a
+            .FarAddr handler_b, $123456
+            .byte <handler_a, >handler_b
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "bd 0b 80 48 bd 09 80 48 60 0c 0d 80 80 60 60 0d 80 0e 80 34 12 80 0d 0e 80 00 56 34 12 0d 80");
+
+    assert_errors(&[
+        (".org $1000\n.byte later\nlater:", |e| matches!(e, AsmError::RANGE { .. })),
+        (".faraddr \"ab\"", |e| matches!(e, AsmError::PARSE { .. })),
+        (".word $10000", |e| matches!(e, AsmError::RANGE { .. })),
+        (".dbyt missing", |e| matches!(e, AsmError::UNDEFINED { .. })),
+    ]);
+}
+
+#[test]
+fn accumulator_mode() {
+    let source =String::from(r##"
+        a = $10
+            ASL A               ; 0a
+            lsr a               ; 4a
+            ROL                 ; 2a
+            ROR A ; rotate      ; 6a
+            LDA a               ; a5 10
+            ASL a + 1           ; 06 11
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "0a 4a 2a 6a a5 10 06 11");
+
+    assert_errors(&[("INX A", |e| matches!(e, AsmError::OPCODE { .. }))]);
+}
+
+#[test]
+fn width_overrides() {
+    let source =String::from(r##"
+        zp = $10
+            LDA a:$0010         ; ad 10 00
+            LDA a:$10           ; ad 10 00
+            LDA.w $10           ; ad 10 00
+            LDA z:$0010         ; a5 10
+            LDA.b $0010,x       ; b5 10
+            STA z:zp+1,x        ; 95 11
+            LDA z:table         ; a5 14
+            LDX a:table,y       ; be 14 00
+        table:
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "ad 10 00 ad 10 00 ad 10 00 a5 10 b5 10 95 11 a5 14 be 14 00");
+
+    assert_errors(&[
+        ("LDA z:$1234", |e| matches!(e, AsmError::RANGE { .. })),
+        ("JMP z:$10", |e| matches!(e, AsmError::OPCODE { .. })),
+        ("LDA.q $10", |e| matches!(e, AsmError::PARSE { .. })),
+        ("LDA.b #1", |e| matches!(e, AsmError::PARSE { .. })),
+        ("LDA.w z:$10", |e| matches!(e, AsmError::PARSE { .. })),
+        (".org $1000\nLDA z:label\nlabel:", |e| matches!(e, AsmError::RANGE { .. })),
+    ]);
+}
+
+#[test]
+fn zero_page_selection() {
+    let source =String::from(r##"
+            .org $8000
+            LDA player_x        ; a5 10
+            STA player_y,x      ; 95 11
+            LDX player_x,y      ; b6 10
+            LDA player_x,y      ; b9 10 00, no zero page y for LDA
+            LDA a:player_x      ; ad 10 00
+            JMP code            ; 4c 0f 80
+        code:
+            LDA code            ; ad 0f 80
+            .org $10
+        player_x: .res 1
+        player_y: .res 1
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "a5 10 95 11 b6 10 b9 10 00 ad 10 00 4c 0f 80 ad 0f 80 00 00");
+
+    // symbols are sized by value wherever they are defined, literals by their digits
+    let source =String::from(r##"
+        before = $0010
+            LDA before          ; a5 10
+            LDA after           ; a5 10
+            LDA before + $0100  ; ad 10 01
+            LDA $0010           ; ad 10 00
+        after = $0010
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "a5 10 a5 10 ad 10 01 ad 10 00");
+
+    // no zero page mode, small values stay absolute
+    let source =String::from(r##"
+        tbl = $0080
+        PORT = $0020
+            LDA tbl,y           ; b9 80 00
+            STA tbl,y           ; 99 80 00
+            JMP PORT            ; 4c 20 00
+            JSR PORT            ; 20 20 00
+            LDX tbl,y           ; b6 80
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "b9 80 00 99 80 00 4c 20 00 20 20 00 b6 80");
+
+    // fits in zero page only while absolute
+    let mut compiler = Compiler::new(None);
+    compiler.init_source("LDA $102 - end\nend:").unwrap();
+    match compiler.to_byte_code() {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => {
+            assert!(matches!(&e, AsmError::SYMBOL { span, .. } if span.line == 1));
+            assert!(e.to_string().contains("did not settle"));
+        }
+    }
+}
+
+#[test]
+fn branch_relaxation() {
+    let source =String::from(r##"
+            .org $8000
+        start:
+            JEQ near            ; f0 07
+            BNE far             ; f0 03 4c d1 80
+            JNE start           ; d0 f7
+        near:
+            .res 200
+        far:
+            BCC near            ; b0 03 4c 09 80
+    "##);
+    let mut compiler = Compiler::new(Some(CompilerConfig {
+        relax_branches: true,
+        ..Default::default()
+    }));
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    let padding = vec!["00"; 200].join(" ");
+    assert_eq!(hex_string, format!("f0 07 f0 03 4c d1 80 d0 f7 {} b0 03 4c 09 80", padding));
+    assert_eq!(compiler.relaxed_branches(), 2);
+
+    // only pseudo branches are relaxed by default
+    let mut compiler = Compiler::new(None);
+    compiler.init_source("JNE far\n.res 200\nfar:").unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert!(hex_string.starts_with("f0 03 4c cd 00"));
+    assert_eq!(compiler.relaxed_branches(), 1);
+
+    assert_errors(&[("BNE far\n.res 200\nfar:", |e| matches!(e, AsmError::RANGE { .. }))]);
+}
+
+#[test]
+fn procs_and_scopes() {
+    let source =String::from(r##"
+            JSR main            ; 20 06 00
+            JMP main::loop      ; 4c 08 00
+        .proc main
+            LDX #$02
+        loop:
+            DEX
+            BNE loop            ; d0 fd
+        @done:
+            RTS
+        .endproc
+        .proc helper
+        loop:
+            LDY #$01
+            BNE loop            ; d0 fc
+            JMP main            ; 4c 06 00
+        .endproc
+        .scope
+        loop:
+            NOP
+            JMP loop            ; 4c 13 00
+        .endscope
+        .SCOPE data
+        value:
+            .byte $2a
+        .EndScope
+            LDA data::value     ; a5 17
+        .proc outer
+        .scope inner
+        x:  RTS
+        .endscope
+            JMP inner::x        ; 4c 1a 00
+        .endproc
+            JMP outer::inner::x ; 4c 1a 00
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "20 06 00 4c 08 00 a2 02 ca d0 fd 60 a0 01 d0 fc 4c 06 00 ea 4c 13 00 2a a5 17 60 4c 1a 00 4c 1a 00");
+
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(".proc main\nloop: RTS\n.endproc\nJMP loop").unwrap();
+    match compiler.to_byte_code() {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => assert!(matches!(&e, AsmError::UNDEFINED { name, .. } if name == "loop"))
+    }
+
+    let mut compiler = Compiler::new(None);
+    compiler.init_source("NOP\n.proc main\nRTS").unwrap();
+    match compiler.to_byte_code() {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => {
+            assert!(matches!(&e, AsmError::DIRECTIVE { span, .. } if span.line == 2));
+            assert!(e.to_string().contains(".proc main is never closed by .endproc"));
+        }
     }
-}
\ No newline at end of file
+
+    assert_errors(&[
+        (".proc main\n.endscope", |e| matches!(e, AsmError::DIRECTIVE { .. })),
+        (".scope\n.endproc", |e| matches!(e, AsmError::DIRECTIVE { .. })),
+        (".endproc", |e| matches!(e, AsmError::DIRECTIVE { .. })),
+        (".proc a\n.endproc\n.proc a\n.endproc", |e| matches!(e, AsmError::SYMBOL { .. })),
+    ]);
+}
+
+#[test]
+fn anonymous_labels() {
+    let source =String::from(r##"
+        start:
+            LDX #$08
+        :   DEX                 ; :0
+            BEQ :+              ; f0 03
+            JMP :-              ; 4c 02 00
+        :                       ; :1
+            LDY #$02
+        :   DEY                 ; :2
+            BNE :-              ; d0 fd
+            BEQ :++             ; f0 06
+            JMP :--             ; 4c 08 00
+        :   JMP :+ +1           ; 4c 16 00
+        :   NOP
+        @local:
+            BNE @local          ; start is still the scope
+    "##);
+    let mut compiler = Compiler::new(None);
+    compiler.init_source(&source).unwrap();
+    let hex_string = compiler.to_hex_string().unwrap();
+    assert_eq!(hex_string, "a2 08 ca f0 03 4c 02 00 a0 02 88 d0 fd f0 06 4c 08 00 4c 16 00 ea d0 fe");
+
+    assert_errors(&[
+        ("BNE :-\n:", |e| matches!(e, AsmError::SYMBOL { .. })),
+        (":\nBNE :+", |e| matches!(e, AsmError::SYMBOL { .. })),
+        (":\nBNE : +", |e| matches!(e, AsmError::PARSE { .. })),
+        ("LDA #:", |e| matches!(e, AsmError::PARSE { .. })),
+    ]);
+}
@@ -54,6 +54,24 @@ fn basic_lexing() {
     assert_eq!(res.unwrap(), tokens);
 }
 
+#[test]
+fn operator_lexing() {
+    let mut lexer = AsmLexer::new(&String::from(r##"
+        a % b %01 & && | || ^ ~ ! != << >> < > <= >= <> =
+    "##));
+    let res = lexer.tokenize();
+
+    let tokens = vec![
+        Token::LITERAL("a".to_string()), Token::MOD, Token::LITERAL("b".to_string()),
+        Token::BIN("01".to_string()), Token::AND, Token::LAND, Token::OR, Token::LOR,
+        Token::XOR, Token::NOT, Token::LNOT, Token::NOTEQUAL, Token::SHL, Token::SHR,
+        Token::LT, Token::GT, Token::LTE, Token::GTE, Token::NOTEQUAL, Token::EQUAL,
+        Token::EOF
+    ];
+
+    assert_eq!(res.unwrap(), tokens);
+}
+
 #[test]
 fn simple_parsing() {
     let mut lexer = AsmLexer::new(&String::from(r##"
@@ -72,7 +90,7 @@ fn simple_parsing() {
     let mut parser = AsmParser::new(&tokens);
     let prog = parser.parse();
     let lines = vec![
-        Expr::DIRECTIVE(Directive::RESERVE(1234)),
+        Expr::DIRECTIVE(Directive::RESERVE(MathExpr::NUM(NumericValue { value: 1234, size: 16 }), 0)),
         Expr::DIRECTIVE(Directive::SEGMENT("SOME SEGMENT".to_string())), 
         Expr::ASSIGN(
             "x".to_string(), 
@@ -88,16 +106,16 @@ fn simple_parsing() {
         ), 
         Expr::DIRECTIVE(
             Directive::BYTE(vec![
-                NumericValue { value: 65, size: 8 }, 
-                NumericValue { value: 66, size: 8 }, 
-                NumericValue { value: 67, size: 8 }, 
-                NumericValue { value: 68, size: 8 }, 
-                NumericValue { value: 15, size: 8 }
+                Operand::VALUE(NumericValue { value: 65, size: 8 }), 
+                Operand::VALUE(NumericValue { value: 66, size: 8 }), 
+                Operand::VALUE(NumericValue { value: 67, size: 8 }), 
+                Operand::VALUE(NumericValue { value: 68, size: 8 }), 
+                Operand::VALUE(NumericValue { value: 15, size: 8 })
             ])
         ), 
         Expr::DIRECTIVE(
             Directive::DWORD(vec![
-                NumericValue { value: 17220, size: 16 }
+                Operand::VALUE(NumericValue { value: 17220, size: 16 })
             ])
         ), 
         Expr::ASSIGN(
@@ -117,7 +135,36 @@ fn simple_parsing() {
                     )
                 )), 
         Expr::LABEL("start".to_string()), 
-        Expr::INSTR(Instr::BNE, AdrMode::REL, Operand::LABEL("start".to_string())),
+        Expr::INSTR(Instr::BNE, AdrMode::REL, Operand::LABEL("start".to_string()), false),
     ];
     assert_eq!(prog.unwrap(), lines);
-}
\ No newline at end of file
+}
+#[test]
+fn error_location() {
+    let mut lexer = AsmLexer::new(&String::from("\n  LDA #$10\n  LDX ?\n"));
+    match lexer.tokenize() {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => assert_eq!(e.to_string(), [
+            "error: '?' is not a supported character",
+            " --> <source>:3:7",
+            "  |",
+            "3 |   LDX ?",
+            "  |       ^",
+        ].join("\n"))
+    }
+
+    let source = (0..9).map(|_| "NOP\n").collect::<String>() + "\tLDA #$10, x ; comment";
+    let mut lexer = AsmLexer::new(&source);
+    let tokens = lexer.tokenize_spanned().unwrap();
+    let mut parser = AsmParser::from_spanned(tokens);
+    match parser.parse() {
+        Ok(_) => panic!("error was expected"),
+        Err(e) => assert_eq!(e.to_string(), [
+            "error: token COMMA unexpected",
+            "  --> <source>:10:10",
+            "   |",
+            "10 | \tLDA #$10, x ; comment",
+            "   | \t        ^",
+        ].join("\n"))
+    }
+}